@@ -0,0 +1,255 @@
+// Authorization of the requests.
+// The ACLs allow or deny a principal an operation on a topic, a group or the cluster, the way Kafka's
+// AclAuthorizer does. They are kept in the "__acls" table and managed with CreateAcls, DescribeAcls and DeleteAcls.
+
+use std::sync::RwLock;
+use parser::{AclBinding, AclFilter};
+
+// Resource types
+pub const ANY: i8 = 1; // the filters only, same code for the pattern, operation and permission types
+pub const TOPIC: i8 = 2;
+pub const GROUP: i8 = 3;
+pub const CLUSTER: i8 = 4;
+
+// Pattern types
+pub const MATCH: i8 = 2; // the filters only, whatever applies to the name
+pub const LITERAL: i8 = 3;
+pub const PREFIXED: i8 = 4;
+
+// Operations
+pub const ALL: i8 = 2;
+pub const READ: i8 = 3;
+pub const WRITE: i8 = 4;
+pub const CREATE: i8 = 5;
+pub const DELETE: i8 = 6;
+pub const ALTER: i8 = 7;
+pub const DESCRIBE: i8 = 8;
+
+// Permission types
+pub const DENY: i8 = 2;
+pub const ALLOW: i8 = 3;
+
+// The name of the only cluster resource
+pub const CLUSTER_NAME: &'static str = "kafka-cluster";
+
+// The principal of the connections that did not authenticate
+pub const ANONYMOUS: &'static str = "User:ANONYMOUS";
+
+const WILDCARD: &'static str = "*";
+const WILDCARD_PRINCIPAL: &'static str = "User:*";
+
+#[derive(Debug)]
+pub struct Authorizer {
+    enabled: bool, // everything is allowed when not
+    super_users: Vec<String>,
+    allow_if_no_acl: bool, // for the resources no ACL is about
+    acls: RwLock<Vec<AclBinding>>
+}
+
+// Who the request comes from, the handlers check it against the ACLs
+pub struct Access<'a> {
+    authorizer: &'a Authorizer,
+    principal: String,
+    host: String
+}
+
+impl<'a> Access<'a> {
+    pub fn allows(&self, operation: i8, resource_type: i8, name: &str) -> bool {
+        self.authorizer.authorize(&self.principal, &self.host, operation, resource_type, name)
+    }
+}
+
+impl Authorizer {
+    pub fn new(enabled: bool, super_users: Vec<String>, allow_if_no_acl: bool, acls: Vec<AclBinding>) -> Authorizer {
+        Authorizer {
+            enabled: enabled,
+            super_users: super_users,
+            allow_if_no_acl: allow_if_no_acl,
+            acls: RwLock::new(acls)
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn access<'a>(&'a self, principal: String, host: String) -> Access<'a> {
+        Access {
+            authorizer: self,
+            principal: principal,
+            host: host
+        }
+    }
+
+    // A DENY wins over any ALLOW. Read, Write, Delete and Alter allow Describe as well.
+    pub fn authorize(&self, principal: &str, host: &str, operation: i8, resource_type: i8, name: &str) -> bool {
+        if !self.enabled || self.super_users.iter().any(|u| u == principal) {
+            return true;
+        }
+        let acls = self.acls.read().unwrap();
+        let applicable: Vec<&AclBinding> = acls.iter().filter(|acl| applies(acl, resource_type, name)).collect();
+        let allowed = if applicable.is_empty() {
+            self.allow_if_no_acl
+        } else {
+            let theirs: Vec<&AclBinding> = applicable.into_iter()
+                .filter(|acl| (acl.principal == principal || acl.principal == WILDCARD_PRINCIPAL) && (acl.host == host || acl.host == WILDCARD))
+                .collect();
+            let implied = |op: i8| op == operation || op == ALL
+                || (operation == DESCRIBE && (op == READ || op == WRITE || op == DELETE || op == ALTER));
+            !theirs.iter().any(|acl| acl.permission_type == DENY && (acl.operation == operation || acl.operation == ALL))
+                && theirs.iter().any(|acl| acl.permission_type == ALLOW && implied(acl.operation))
+        };
+        if !allowed {
+            info!("Principal {} from host {} is denied operation {} on {} {}",
+                  principal, host, operation_name(operation), resource_type_name(resource_type), name);
+        }
+        allowed
+    }
+
+    // Returns false if it is already there
+    pub fn add(&self, acl: AclBinding) -> bool {
+        let mut acls = self.acls.write().unwrap();
+        if acls.contains(&acl) {
+            return false;
+        }
+        acls.push(acl);
+        true
+    }
+
+    pub fn remove(&self, acl: &AclBinding) {
+        self.acls.write().unwrap().retain(|a| a != acl);
+    }
+
+    pub fn find(&self, filter: &AclFilter) -> Vec<AclBinding> {
+        self.acls.read().unwrap().iter().filter(|acl| matches(filter, acl)).cloned().collect()
+    }
+}
+
+// Whether the ACL is about the resource, whoever it is for
+fn applies(acl: &AclBinding, resource_type: i8, name: &str) -> bool {
+    acl.resource_type == resource_type && match acl.pattern_type {
+        LITERAL => acl.resource_name == name || acl.resource_name == WILDCARD,
+        PREFIXED => name.starts_with(&acl.resource_name),
+        _ => false
+    }
+}
+
+// The filters of DescribeAcls and DeleteAcls
+pub fn matches(filter: &AclFilter, acl: &AclBinding) -> bool {
+    let name = filter.resource_name.as_ref();
+    let pattern = match filter.pattern_type {
+        ANY => name.map(|n| n == &acl.resource_name).unwrap_or(true),
+        MATCH => name.map(|n| applies(acl, acl.resource_type, n)).unwrap_or(true),
+        pattern_type => acl.pattern_type == pattern_type && name.map(|n| n == &acl.resource_name).unwrap_or(true)
+    };
+    pattern
+        && (filter.resource_type == ANY || filter.resource_type == acl.resource_type)
+        && filter.principal.as_ref().map(|p| p == &acl.principal).unwrap_or(true)
+        && filter.host.as_ref().map(|h| h == &acl.host).unwrap_or(true)
+        && (filter.operation == ANY || filter.operation == acl.operation)
+        && (filter.permission_type == ANY || filter.permission_type == acl.permission_type)
+}
+
+// What CreateAcls accepts, the message goes back to the client
+pub fn validate(acl: &AclBinding) -> Result<(), String> {
+    if acl.resource_type != TOPIC && acl.resource_type != GROUP && acl.resource_type != CLUSTER {
+        return Err(format!("Unsupported resource type {}", acl.resource_type));
+    }
+    if acl.pattern_type != LITERAL && acl.pattern_type != PREFIXED {
+        return Err(format!("Unsupported pattern type {}", acl.pattern_type));
+    }
+    if acl.resource_type == CLUSTER && (acl.pattern_type != LITERAL || acl.resource_name != CLUSTER_NAME) {
+        return Err(format!("The cluster resource is named {}", CLUSTER_NAME));
+    }
+    if acl.resource_name.is_empty() || (acl.pattern_type == PREFIXED && acl.resource_name == WILDCARD) {
+        return Err(format!("Invalid resource name {}", acl.resource_name));
+    }
+    if acl.operation < ALL || acl.operation > DESCRIBE {
+        return Err(format!("Unsupported operation {}", acl.operation));
+    }
+    if acl.permission_type != ALLOW && acl.permission_type != DENY {
+        return Err(format!("Unsupported permission type {}", acl.permission_type));
+    }
+    if !acl.principal.starts_with("User:") || acl.principal.len() == 5 {
+        return Err(format!("Invalid principal {}, it should look like User:name", acl.principal));
+    }
+    if acl.host.is_empty() {
+        return Err("The host must not be empty, use * for any".to_string());
+    }
+    Ok(())
+}
+
+fn operation_name(operation: i8) -> &'static str {
+    match operation {
+        ALL => "All",
+        READ => "Read",
+        WRITE => "Write",
+        CREATE => "Create",
+        DELETE => "Delete",
+        ALTER => "Alter",
+        DESCRIBE => "Describe",
+        _ => "Unknown"
+    }
+}
+
+fn resource_type_name(resource_type: i8) -> &'static str {
+    match resource_type {
+        TOPIC => "topic",
+        GROUP => "group",
+        CLUSTER => "cluster",
+        _ => "resource"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(resource_type: i8, name: &str, pattern_type: i8, principal: &str, operation: i8, permission_type: i8) -> AclBinding {
+        AclBinding {
+            resource_type: resource_type,
+            resource_name: name.to_string(),
+            pattern_type: pattern_type,
+            principal: principal.to_string(),
+            host: WILDCARD.to_string(),
+            operation: operation,
+            permission_type: permission_type
+        }
+    }
+
+    #[test]
+    fn deny_wins_and_read_implies_describe() {
+        let authorizer = Authorizer::new(true, vec!["User:admin".to_string()], false, vec![
+            acl(TOPIC, "orders", PREFIXED, "User:alice", READ, ALLOW),
+            acl(TOPIC, "orders-secret", LITERAL, "User:*", ALL, DENY),
+            acl(GROUP, "*", LITERAL, "User:alice", READ, ALLOW)
+        ]);
+        assert!(authorizer.authorize("User:alice", "10.0.0.1", READ, TOPIC, "orders-eu"));
+        assert!(authorizer.authorize("User:alice", "10.0.0.1", DESCRIBE, TOPIC, "orders-eu"));
+        assert!(!authorizer.authorize("User:alice", "10.0.0.1", WRITE, TOPIC, "orders-eu"));
+        assert!(!authorizer.authorize("User:alice", "10.0.0.1", READ, TOPIC, "orders-secret"));
+        assert!(!authorizer.authorize("User:bob", "10.0.0.1", READ, TOPIC, "orders-eu"));
+        assert!(authorizer.authorize("User:alice", "10.0.0.1", READ, GROUP, "anything"));
+        assert!(!authorizer.authorize("User:alice", "10.0.0.1", READ, TOPIC, "payments")); // no ACL at all
+        assert!(authorizer.authorize("User:admin", "10.0.0.1", ALTER, CLUSTER, CLUSTER_NAME));
+    }
+
+    #[test]
+    fn filters_match_like_kafka() {
+        let prefixed = acl(TOPIC, "orders", PREFIXED, "User:alice", READ, ALLOW);
+        let filter = |name: Option<&str>, pattern_type: i8| AclFilter {
+            resource_type: ANY,
+            resource_name: name.map(|n| n.to_string()),
+            pattern_type: pattern_type,
+            principal: None,
+            host: None,
+            operation: ANY,
+            permission_type: ANY
+        };
+        assert!(matches(&filter(None, ANY), &prefixed));
+        assert!(matches(&filter(Some("orders"), ANY), &prefixed));
+        assert!(matches(&filter(Some("orders-eu"), MATCH), &prefixed));
+        assert!(!matches(&filter(Some("orders-eu"), PREFIXED), &prefixed));
+        assert!(!matches(&filter(Some("orders"), LITERAL), &prefixed));
+    }
+}
@@ -0,0 +1,349 @@
+// Authentication of the connections.
+// SASL/PLAIN checks the username and password against the users file named by "sasl.users" in unclek.toml.
+// SASL/SCRAM (RFC 5802, 7677) checks them against the salted credentials kept in the "__scram_credentials" table.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str;
+use config::{Config, File};
+use sha2::{Digest, Sha256, Sha512};
+use hmac::{Hmac, Mac};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rand;
+
+pub const PLAIN: &'static str = "PLAIN";
+
+// The iteration counts Kafka accepts for the SCRAM credentials
+pub const SCRAM_MIN_ITERATIONS: i32 = 4096;
+pub const SCRAM_MAX_ITERATIONS: i32 = 16384;
+
+#[derive(Debug, Deserialize)]
+struct User {
+    name: String,
+    password: String
+}
+
+#[derive(Debug, Deserialize)]
+struct UsersFile {
+    users: Vec<User>
+}
+
+pub struct Users {
+    passwords: HashMap<String, String>
+}
+
+// Keep the passwords out of the logs
+impl fmt::Debug for Users {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.passwords.keys()).finish()
+    }
+}
+
+impl Users {
+    pub fn load(path: &str) -> Users {
+        let mut s = Config::new();
+        s.merge(File::with_name(path)).expect("Failed to read the users file");
+        let file: UsersFile = s.try_into().expect("Failed to parse the users file");
+        Users {
+            passwords: file.users.into_iter().map(|u| (u.name, u.password)).collect()
+        }
+    }
+
+    // The PLAIN message is [authzid] NUL authcid NUL passwd (RFC 4616). Returns the authenticated user.
+    pub fn plain(&self, token: &[u8]) -> Option<String> {
+        let parts: Vec<&[u8]> = token.split(|b| *b == 0).collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        let (authzid, authcid, passwd) = (parts[0], parts[1], parts[2]);
+        // Acting on behalf of another user is not supported
+        if !authzid.is_empty() && authzid != authcid {
+            return None;
+        }
+        let user = match str::from_utf8(authcid) {
+            Ok(user) => user,
+            Err(_) => return None
+        };
+        match self.passwords.get(user) {
+            Some(password) if constant_time_eq(password.as_bytes(), passwd) => Some(user.to_string()),
+            _ => None
+        }
+    }
+}
+
+// The principal name the rest of the server knows the user by
+pub fn principal(user: &str) -> String {
+    format!("User:{}", user)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scram {
+    Sha256,
+    Sha512
+}
+
+impl Scram {
+    pub fn from_name(name: &str) -> Option<Scram> {
+        match name {
+            "SCRAM-SHA-256" => Some(Scram::Sha256),
+            "SCRAM-SHA-512" => Some(Scram::Sha512),
+            _ => None
+        }
+    }
+
+    // The mechanism numbers of the UserScramCredentials requests
+    pub fn from_type(t: i8) -> Option<Scram> {
+        match t {
+            1 => Some(Scram::Sha256),
+            2 => Some(Scram::Sha512),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Scram::Sha256 => "SCRAM-SHA-256",
+            Scram::Sha512 => "SCRAM-SHA-512"
+        }
+    }
+
+    pub fn type_id(&self) -> i8 {
+        match *self {
+            Scram::Sha256 => 1,
+            Scram::Sha512 => 2
+        }
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Scram::Sha256 => Sha256::digest(data).to_vec(),
+            Scram::Sha512 => Sha512::digest(data).to_vec()
+        }
+    }
+
+    fn hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match *self {
+            Scram::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+            Scram::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    // What the server keeps, the password itself is not needed to verify the clients
+    pub fn credential(&self, salted_password: &[u8], salt: &[u8], iterations: i32) -> ScramCredential {
+        ScramCredential {
+            salt: salt.to_vec(),
+            stored_key: self.hash(&self.hmac(salted_password, b"Client Key")),
+            server_key: self.hmac(salted_password, b"Server Key"),
+            iterations: iterations
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScramCredential {
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+    pub iterations: i32
+}
+
+// The client-first-message: gs2-header client-first-message-bare
+#[derive(Debug)]
+pub struct ScramClientFirst {
+    pub user: String,
+    gs2_header: String,
+    bare: String,
+    nonce: String
+}
+
+// Between the server-first-message and the client-final-message
+#[derive(Debug)]
+pub struct ScramExchange {
+    mechanism: Scram,
+    pub user: String,
+    credential: ScramCredential,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String
+}
+
+// The saslname of the RFC escapes ',' and '='
+fn scram_name(name: &str) -> Option<String> {
+    let unescaped = name.replace("=2C", ",").replace("=3D", "=");
+    if unescaped.len() + 2 * name.matches('=').count() != name.len() {
+        return None; // '=' not followed by 2C or 3D
+    }
+    Some(unescaped)
+}
+
+impl ScramClientFirst {
+    pub fn parse(msg: &[u8]) -> Result<ScramClientFirst, String> {
+        let msg = str::from_utf8(msg).map_err(|_| "Invalid SCRAM message".to_string())?;
+        let parts: Vec<&str> = msg.splitn(3, ',').collect();
+        if parts.len() != 3 {
+            return Err("Invalid SCRAM client first message".to_string());
+        }
+        // No channel binding, it is not something a Kafka client does
+        if parts[0] != "n" && parts[0] != "y" {
+            return Err("SCRAM channel binding is not supported".to_string());
+        }
+        let bare = parts[2];
+        let mut attributes = bare.split(',');
+        let user = match attributes.next() {
+            Some(a) if a.starts_with("n=") => scram_name(&a[2..]).ok_or_else(|| "Invalid SCRAM user name".to_string())?,
+            _ => return Err("Invalid SCRAM client first message".to_string())
+        };
+        let nonce = match attributes.next() {
+            Some(a) if a.starts_with("r=") && a.len() > 2 => a[2..].to_string(),
+            _ => return Err("Invalid SCRAM client first message".to_string())
+        };
+        // Acting on behalf of another user is not supported
+        if !parts[1].is_empty() && parts[1] != format!("a={}", &bare[2..].split(',').next().unwrap_or("")) {
+            return Err("SCRAM authorization id does not match the user".to_string());
+        }
+        if attributes.any(|a| a == "tokenauth=true") {
+            return Err("Delegation tokens are not supported".to_string());
+        }
+        Ok(ScramClientFirst {
+            user: user,
+            gs2_header: format!("{},{},", parts[0], parts[1]),
+            bare: bare.to_string(),
+            nonce: nonce
+        })
+    }
+
+    // Returns the exchange and the server-first-message
+    pub fn respond(self, mechanism: Scram, credential: ScramCredential) -> (ScramExchange, Vec<u8>) {
+        let server_nonce = BASE64.encode(&rand::random::<[u8; 24]>());
+        self.respond_with_nonce(mechanism, credential, &server_nonce)
+    }
+
+    fn respond_with_nonce(self, mechanism: Scram, credential: ScramCredential, server_nonce: &str) -> (ScramExchange, Vec<u8>) {
+        let nonce = format!("{}{}", self.nonce, server_nonce);
+        let server_first = format!("r={},s={},i={}", nonce, BASE64.encode(&credential.salt), credential.iterations);
+        let reply = server_first.as_bytes().to_vec();
+        (ScramExchange {
+            mechanism: mechanism,
+            user: self.user,
+            credential: credential,
+            gs2_header: self.gs2_header,
+            client_first_bare: self.bare,
+            server_first: server_first,
+            nonce: nonce
+        }, reply)
+    }
+}
+
+impl ScramExchange {
+    // Checks the proof of the client-final-message, returns the server-final-message
+    pub fn finish(self, msg: &[u8]) -> Result<Vec<u8>, String> {
+        let msg = str::from_utf8(msg).map_err(|_| "Invalid SCRAM message".to_string())?;
+        let (without_proof, proof) = match msg.rfind(",p=") {
+            Some(i) => (&msg[..i], &msg[i + 3..]),
+            None => return Err("Invalid SCRAM client final message".to_string())
+        };
+        let mut attributes = without_proof.split(',');
+        if attributes.next() != Some(&format!("c={}", BASE64.encode(self.gs2_header.as_bytes()))[..]) {
+            return Err("Invalid SCRAM channel binding".to_string());
+        }
+        if attributes.next() != Some(&format!("r={}", self.nonce)[..]) {
+            return Err("Invalid SCRAM nonce".to_string());
+        }
+        let proof = BASE64.decode(proof).map_err(|_| "Invalid SCRAM proof".to_string())?;
+        let auth_message = format!("{},{},{}", self.client_first_bare, self.server_first, without_proof);
+        let client_signature = self.mechanism.hmac(&self.credential.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err("Invalid SCRAM proof".to_string());
+        }
+        let client_key: Vec<u8> = proof.iter().zip(client_signature.iter()).map(|(p, s)| p ^ s).collect();
+        if !constant_time_eq(&self.mechanism.hash(&client_key), &self.credential.stored_key) {
+            return Err("Authentication failed: Invalid user credentials".to_string());
+        }
+        let server_signature = self.mechanism.hmac(&self.credential.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", BASE64.encode(&server_signature)).into_bytes())
+    }
+}
+
+// Does not tell how much of a password matched by the time it takes
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pbkdf2::pbkdf2_hmac;
+
+    // Hi() of the RFC, the clients send the salted passwords already
+    fn salted_password(mechanism: Scram, password: &[u8], salt: &[u8], iterations: i32) -> Vec<u8> {
+        match mechanism {
+            Scram::Sha256 => {
+                let mut out = vec![0; 32];
+                pbkdf2_hmac::<Sha256>(password, salt, iterations as u32, &mut out);
+                out
+            },
+            Scram::Sha512 => {
+                let mut out = vec![0; 64];
+                pbkdf2_hmac::<Sha512>(password, salt, iterations as u32, &mut out);
+                out
+            }
+        }
+    }
+
+    fn users() -> Users {
+        let mut passwords = HashMap::new();
+        passwords.insert("alice".to_string(), "alice-secret".to_string());
+        Users { passwords: passwords }
+    }
+
+    #[test]
+    fn plain_accepts_the_right_password() {
+        assert_eq!(users().plain(b"\0alice\0alice-secret"), Some("alice".to_string()));
+        assert_eq!(users().plain(b"alice\0alice\0alice-secret"), Some("alice".to_string()));
+    }
+
+    // The example exchange of RFC 7677
+    #[test]
+    fn scram_sha_256_exchange() {
+        let mechanism = Scram::Sha256;
+        let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credential = mechanism.credential(&salted_password(mechanism, b"pencil", &salt, 4096), &salt, 4096);
+        let first = ScramClientFirst::parse(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        assert_eq!(first.user, "user");
+        let (exchange, reply) = first.respond_with_nonce(mechanism, credential, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0");
+        assert_eq!(&reply[..], &b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"[..]);
+        let last = exchange.finish(b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        assert_eq!(last, Ok(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_vec()));
+    }
+
+    #[test]
+    fn scram_rejects_a_wrong_proof() {
+        let mechanism = Scram::Sha512;
+        let credential = mechanism.credential(&salted_password(mechanism, b"pencil", b"salt", 4096), b"salt", 4096);
+        let first = ScramClientFirst::parse(b"n,,n=us=2Cer,r=abc").unwrap();
+        assert_eq!(first.user, "us,er");
+        let (exchange, _) = first.respond_with_nonce(mechanism, credential, "def");
+        assert!(exchange.finish(b"c=biws,r=abcdef,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=").is_err());
+        assert!(ScramClientFirst::parse(b"n,,n=us=er,r=abc").is_err());
+        assert!(ScramClientFirst::parse(b"p=tls-unique,,n=user,r=abc").is_err());
+    }
+
+    #[test]
+    fn plain_rejects_the_rest() {
+        assert_eq!(users().plain(b"\0alice\0alice-secreT"), None);
+        assert_eq!(users().plain(b"\0alice\0alice-secret\0"), None);
+        assert_eq!(users().plain(b"bob\0alice\0alice-secret"), None);
+        assert_eq!(users().plain(b"\0bob\0alice-secret"), None);
+        assert_eq!(users().plain(b"alice-secret"), None);
+    }
+}
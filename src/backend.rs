@@ -1,3 +1,4 @@
+use parser;
 use parser::*;
 use writer::*;
 use r2d2_postgres;
@@ -5,73 +6,742 @@ use r2d2;
 use r2d2::Pool;
 use r2d2_postgres::{TlsMode, PostgresConnectionManager};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use futures::sync::oneshot;
+use postgres;
+use postgres::GenericConnection;
+use coordinator::{Coordinator, Joining, Syncing};
+use notifier;
+use notifier::Notifier;
+use auth;
+use auth::{Users, Scram, ScramClientFirst, ScramCredential, ScramExchange};
+use acl;
+use acl::{Access, Authorizer};
+use compression::Compression;
 use settings::Settings;
 use settings::Topic;
+use settings::Listener;
 
 #[derive(Debug, Clone)]
 pub struct PgState {
     pub pool: Pool<r2d2_postgres::PostgresConnectionManager>,
-    pub topics: HashMap<String, Topic>,
-    pub hostname: String,
+    pub topics: Arc<RwLock<HashMap<String, Topic>>>, // CreateTopics adds to them at runtime
+    pub configured_topics: Arc<Vec<String>>, // the ones of the configuration file, DeleteTopics leaves them alone
+    pub listeners: Arc<Vec<Listener>>,
+    pub coordinator: Arc<Coordinator>,
+    pub notifier: Arc<Notifier>,
+    pub offsets_retention: u64,
+    pub delete_topic_enable: bool,
+    pub users: Option<Arc<Users>>,
+    pub sasl_mechanisms: Vec<String>, // the connections have to authenticate with one of them when not empty
+    pub authorizer: Arc<Authorizer>
+}
+
+impl PgState {
+    pub fn topic(&self, name: &str) -> Option<Topic> {
+        self.topics.read().unwrap().get(name).cloned()
+    }
+
+    pub fn listener(&self, name: &str) -> &Listener {
+        self.listeners.iter().find(|l| l.name == name).unwrap_or(&self.listeners[0])
+    }
 }
 
 pub fn initialize(cnf: &Settings) -> PgState {
     let db_url = cnf.database.url.to_string();
     let db_config = r2d2::Config::default();
-    let db_manager = PostgresConnectionManager::new(db_url, TlsMode::None).unwrap();
+    let db_manager = PostgresConnectionManager::new(db_url.as_str(), TlsMode::None).unwrap();
     let db_pool = r2d2::Pool::new(db_config, db_manager).unwrap();
     create_tables(&cnf.topics, &db_pool);
     let mut map = HashMap::new();
+    for topic in load_topics(&db_pool) {
+        map.insert(topic.name.to_string(), topic);
+    }
     for topic in &cnf.topics {
+        if let Some(ref codec) = topic.compression {
+            if Compression::from_name(codec).is_none() {
+                warn!("Unknown compression {} for topic {}, the records will be sent uncompressed", codec, topic.name);
+            }
+        }
+        if map.contains_key(&topic.name) {
+            warn!("Topic {} is both in the configuration and created by CreateTopics, using the configuration", topic.name);
+        }
         map.insert(topic.name.to_string(), topic.clone());
     }
+    let listeners = cnf.listeners();
+    let sasl_mechanisms = sasl_mechanisms(cnf);
+    if sasl_mechanisms.is_empty() && listeners.iter().any(|l| l.protocol.sasl()) {
+        warn!("No SASL mechanisms are enabled, nobody will be able to connect to the SASL listeners");
+    }
+    let authorizer = Authorizer::new(cnf.authorizer_enable.unwrap_or(false), cnf.super_users(),
+                                     cnf.allow_everyone_if_no_acl_found.unwrap_or(false), load_acls(&db_pool));
+    let notifier = Arc::new(Notifier::new());
+    notifier::start(db_url, notifier.clone());
     PgState {
         pool: db_pool,
-        topics: map,
-        hostname: cnf.get_hostname()
-        
+        topics: Arc::new(RwLock::new(map)),
+        configured_topics: Arc::new(cnf.topics.iter().map(|t| t.name.to_string()).collect()),
+        listeners: Arc::new(listeners),
+        coordinator: Arc::new(Coordinator::new()),
+        notifier: notifier,
+        offsets_retention: cnf.offsets_retention.unwrap_or(24 * 60 * 60 * 1000),
+        delete_topic_enable: cnf.delete_topic_enable.unwrap_or(true),
+        users: cnf.sasl_users.as_ref().map(|path| Arc::new(Users::load(path))),
+        sasl_mechanisms: sasl_mechanisms,
+        authorizer: Arc::new(authorizer)
     }
 }
 
+// PLAIN is enabled by the users file alone, SCRAM has to be asked for
+fn sasl_mechanisms(cnf: &Settings) -> Vec<String> {
+    let default = if cnf.sasl_users.is_some() { vec![auth::PLAIN.to_string()] } else { Vec::new() };
+    let mechanisms = cnf.sasl_enabled_mechanisms.clone().unwrap_or(default);
+    mechanisms.into_iter().filter(|m| {
+        if m == auth::PLAIN && cnf.sasl_users.is_none() {
+            warn!("SASL mechanism PLAIN needs the sasl.users file, disabling it");
+            false
+        } else if m != auth::PLAIN && Scram::from_name(m).is_none() {
+            warn!("Unknown SASL mechanism {}, ignoring it", m);
+            false
+        } else {
+            true
+        }
+    }).collect()
+}
+
+// On a transaction for the topics created at runtime
+fn create_topic_table(topic: &Topic, conn: &GenericConnection) -> postgres::Result<()> {
+    let uniq = if topic.compacted.unwrap_or(false) {", UNIQUE (partition, key)"} else {""};
+    // TODO if topic has a defined retention we may need an index on "ts"
+    conn.execute(format!(r#"
+        CREATE TABLE IF NOT EXISTS "{}" (
+            partition int NOT NULL,
+            "offset" bigint NOT NULL,
+            ts timestamp NOT NULL,
+            "timestamp" bigint NOT NULL,
+            key BYTEA,
+            value BYTEA,
+            header_keys text[],
+            header_values BYTEA[],
+            PRIMARY KEY (partition, "offset") {})
+        "#,
+        topic.name, uniq).as_str(), &[])?;
+    // ListOffsets looks the offsets up by the message timestamp
+    conn.execute(format!(r#"CREATE INDEX IF NOT EXISTS "{}_timestamp" ON "{}" (partition, "timestamp")"#,
+        topic.name, topic.name).as_str(), &[])?;
+    for partition in 0..topic.partitions() {
+        conn.execute(r#"INSERT INTO "__partitions" (topic, partition, next_offset) VALUES ($1, $2, 0)
+                        ON CONFLICT DO NOTHING"#,
+            &[&topic.name, &(partition as i32)])?;
+    }
+    Ok(())
+}
+
+// The topic tables of the older versions: the first ones had a global "id" instead of the partition offsets,
+// the timestamps and the headers came later. The ids become the offsets, so the committed offsets stay valid.
+fn migrate_topic_table(topic: &Topic, conn: &r2d2::PooledConnection<PostgresConnectionManager>) {
+    let columns: Vec<String> = conn.query(r#"SELECT column_name::text FROM information_schema.columns
+                                             WHERE table_schema = current_schema() AND table_name = $1"#, &[&topic.name])
+        .expect("DB query failed").iter().map(|row| row.get(0)).collect();
+    let missing: Vec<&str> = ["offset", "timestamp", "header_keys"].iter().cloned()
+        .filter(|column| !columns.is_empty() && !columns.iter().any(|c| c == column))
+        .collect();
+    if missing.is_empty() {
+        return; // up to date or not there yet
+    }
+    info!("Migrating the table of topic {} to the current layout", topic.name);
+    if let Err(e) = migrate_columns(topic, &missing, conn) {
+        panic!("Failed to migrate the table of topic {}, please migrate it by hand: {}", topic.name, e);
+    }
+}
+
+fn migrate_columns(topic: &Topic, missing: &Vec<&str>, conn: &r2d2::PooledConnection<PostgresConnectionManager>) -> Result<(), postgres::Error> {
+    let tx = conn.transaction()?;
+    let mut statements: Vec<String> = Vec::new();
+    if missing.contains(&"offset") {
+        statements.push(format!(r#"ALTER TABLE "{}" ADD COLUMN "offset" bigint"#, topic.name));
+        statements.push(format!(r#"UPDATE "{}" SET "offset" = id"#, topic.name));
+        statements.push(format!(r#"ALTER TABLE "{}" ALTER COLUMN "offset" SET NOT NULL"#, topic.name));
+        statements.push(format!(r#"ALTER TABLE "{}" DROP COLUMN id"#, topic.name)); // and its primary key
+        statements.push(format!(r#"ALTER TABLE "{}" ADD PRIMARY KEY (partition, "offset")"#, topic.name));
+        if topic.compacted.unwrap_or(false) {
+            // The keys were unique across the partitions
+            statements.push(format!(r#"ALTER TABLE "{}" DROP CONSTRAINT IF EXISTS "{}_key_key""#, topic.name, topic.name));
+            statements.push(format!(r#"ALTER TABLE "{}" ADD UNIQUE (partition, key)"#, topic.name));
+        }
+    }
+    if missing.contains(&"timestamp") {
+        statements.push(format!(r#"ALTER TABLE "{}" ADD COLUMN "timestamp" bigint"#, topic.name));
+        statements.push(format!(r#"UPDATE "{}" SET "timestamp" = (extract(epoch FROM ts) * 1000)::bigint"#, topic.name));
+        statements.push(format!(r#"ALTER TABLE "{}" ALTER COLUMN "timestamp" SET NOT NULL"#, topic.name));
+    }
+    statements.push(format!(r#"ALTER TABLE "{}" ADD COLUMN IF NOT EXISTS header_keys text[]"#, topic.name));
+    statements.push(format!(r#"ALTER TABLE "{}" ADD COLUMN IF NOT EXISTS header_values BYTEA[]"#, topic.name));
+    for statement in &statements {
+        tx.execute(statement.as_str(), &[])?;
+    }
+    if missing.contains(&"offset") {
+        // The next offsets continue after the old ids
+        tx.execute(format!(r#"INSERT INTO "__partitions" (topic, partition, next_offset)
+                              SELECT $1, partition, max("offset") + 1 FROM "{}" GROUP BY partition
+                              ON CONFLICT (topic, partition) DO UPDATE
+                              SET next_offset = GREATEST("__partitions".next_offset, EXCLUDED.next_offset)"#, topic.name).as_str(),
+            &[&topic.name])?;
+    }
+    tx.commit()
+}
+
+fn load_topics(db: &Pool<r2d2_postgres::PostgresConnectionManager>) -> Vec<Topic> {
+    let conn = db.get().expect("Could not get a DB connection");
+    let rs = conn.query(r#"SELECT name, partitions, compacted, retention, compression, timestamp_type FROM "__topics""#, &[])
+        .expect("DB query failed");
+    rs.iter().map(|row| {
+        let partitions: i32 = row.get(1);
+        let retention: Option<i64> = row.get(3);
+        Topic {
+            name: row.get(0),
+            compacted: Some(row.get(2)),
+            retention: retention.map(|r| r as u64),
+            partitions: Some(partitions as u32),
+            compression: row.get(4),
+            timestamp_type: row.get(5)
+        }
+    }).collect()
+}
+
+fn load_acls(db: &Pool<r2d2_postgres::PostgresConnectionManager>) -> Vec<AclBinding> {
+    let conn = db.get().expect("Could not get a DB connection");
+    let rs = conn.query(r#"SELECT resource_type, resource_name, pattern_type, principal, host, operation, permission_type FROM "__acls""#, &[])
+        .expect("DB query failed");
+    rs.iter().map(|row| {
+        let (resource_type, pattern_type, operation, permission_type): (i16, i16, i16, i16) = (row.get(0), row.get(2), row.get(5), row.get(6));
+        AclBinding {
+            resource_type: resource_type as i8,
+            resource_name: row.get(1),
+            pattern_type: pattern_type as i8,
+            principal: row.get(3),
+            host: row.get(4),
+            operation: operation as i8,
+            permission_type: permission_type as i8
+        }
+    }).collect()
+}
+
 fn create_tables(topics: &Vec<Topic>, db: &Pool<r2d2_postgres::PostgresConnectionManager>) {
     let conn = db.get().expect("Could not get a DB connection");
+    // The next offset to be assigned in every partition. Keeps the offsets contiguous within a partition.
+    conn.execute(r#"
+        CREATE TABLE IF NOT EXISTS "__partitions" (
+            topic text NOT NULL,
+            partition int NOT NULL,
+            next_offset bigint NOT NULL,
+            PRIMARY KEY (topic, partition))
+        "#, &[]).expect("Failed to create DB table");
+    // The topics created with CreateTopics, the configured ones are not here
+    conn.execute(r#"
+        CREATE TABLE IF NOT EXISTS "__topics" (
+            name text NOT NULL,
+            partitions int NOT NULL,
+            compacted boolean NOT NULL,
+            retention bigint,
+            compression text,
+            timestamp_type text,
+            created_ts timestamp NOT NULL,
+            PRIMARY KEY (name))
+        "#, &[]).expect("Failed to create DB table");
     for topic in topics {
-        let uniq = if topic.compacted.unwrap_or(false) {"UNIQUE"} else {""};
-        // TODO if topic has a defined retention we may need an index on "ts"
-        conn.execute(format!(r#"
-            CREATE TABLE IF NOT EXISTS "{}" (
-                id bigserial PRIMARY KEY,
-                partition int NOT NULL,
-                ts timestamp NOT NULL,
-                key BYTEA {},
-                value BYTEA)
-            "#,
-            topic.name, uniq).as_str(), &[]).expect("Failed to create DB table");
+        migrate_topic_table(topic, &conn);
+        create_topic_table(topic, &*conn).unwrap_or_else(|e| panic!("Failed to create the DB table of topic {}: {}", topic.name, e));
+    }
+    // Committed consumer group offsets
+    conn.execute(r#"
+        CREATE TABLE IF NOT EXISTS "__consumer_offsets" (
+            group_id text NOT NULL,
+            topic text NOT NULL,
+            partition int NOT NULL,
+            "offset" bigint NOT NULL,
+            metadata text,
+            commit_ts timestamp NOT NULL,
+            expire_ts timestamp NOT NULL,
+            PRIMARY KEY (group_id, topic, partition))
+        "#, &[]).expect("Failed to create DB table");
+    // The client software reported by the connections, ApiVersions v3 and newer
+    conn.execute(r#"
+        CREATE TABLE IF NOT EXISTS "__client_software" (
+            client_id text NOT NULL,
+            software_name text NOT NULL,
+            software_version text NOT NULL,
+            address text NOT NULL,
+            first_seen_ts timestamp NOT NULL,
+            last_seen_ts timestamp NOT NULL,
+            PRIMARY KEY (client_id, software_name, software_version, address))
+        "#, &[]).expect("Failed to create DB table");
+    // SASL/SCRAM credentials, mechanism is 1 for SCRAM-SHA-256 and 2 for SCRAM-SHA-512
+    conn.execute(r#"
+        CREATE TABLE IF NOT EXISTS "__scram_credentials" (
+            name text NOT NULL,
+            mechanism smallint NOT NULL,
+            salt bytea NOT NULL,
+            stored_key bytea NOT NULL,
+            server_key bytea NOT NULL,
+            iterations int NOT NULL,
+            updated_ts timestamp NOT NULL,
+            PRIMARY KEY (name, mechanism))
+        "#, &[]).expect("Failed to create DB table");
+    // The ACLs, the types, operations and permissions are the Kafka codes
+    conn.execute(r#"
+        CREATE TABLE IF NOT EXISTS "__acls" (
+            resource_type smallint NOT NULL,
+            resource_name text NOT NULL,
+            pattern_type smallint NOT NULL,
+            principal text NOT NULL,
+            host text NOT NULL,
+            operation smallint NOT NULL,
+            permission_type smallint NOT NULL,
+            created_ts timestamp NOT NULL,
+            PRIMARY KEY (resource_type, resource_name, pattern_type, principal, host, operation, permission_type))
+        "#, &[]).expect("Failed to create DB table");
+}
+
+// What we know about the client on the other side of a connection
+#[derive(Debug)]
+pub struct Session {
+    pub peer: String,
+    pub listener: String, // the name of the listener the client connected to
+    pub client_software: Option<(String, String)>,
+    pub mechanism: Option<String>, // the SASL mechanism of the handshake
+    pub scram: Option<ScramExchange>, // between the first and the final SCRAM messages
+    pub principal: Option<String>, // who the connection authenticated as
+    pub auth_failed: bool, // the connection gets closed on its next request, no second guess at the password
+    pub joining: Option<String> // the member id of the JoinGroup parked on this connection
+}
+
+impl Session {
+    pub fn new(peer: String, listener: String) -> Session {
+        Session {
+            peer: peer,
+            listener: listener,
+            client_software: None,
+            mechanism: None,
+            scram: None,
+            principal: None,
+            auth_failed: false,
+            joining: None
+        }
+    }
+
+    // The peer address without the port, the ACLs name the hosts this way
+    pub fn host(&self) -> String {
+        self.peer.rsplitn(2, ':').last().unwrap_or("").trim_matches(|c| c == '[' || c == ']').to_string()
+    }
+
+    // The way Kafka reports the client host
+    pub fn client_host(&self) -> String {
+        format!("/{}", self.host())
+    }
+
+    // What the ACLs are checked against
+    pub fn access<'a>(&self, db: &'a PgState) -> Access<'a> {
+        db.authorizer.access(self.principal.clone().unwrap_or_else(|| acl::ANONYMOUS.to_string()), self.host())
     }
 }
 
-pub fn handle_request(req: KafkaRequest, db: &PgState) -> KafkaResponse {
+// A fetch without enough data yet parks until there is new data in its partitions, then it runs again.
+// Once its max wait is over it runs with expired set and answers with whatever there is.
+// JoinGroup and SyncGroup park while they wait for the rest of the group, they run again when the group
+// changes or by the instant the coordinator gives them.
+pub enum Handled {
+    Response(KafkaResponse),
+    Parked(oneshot::Receiver<()>, Option<Instant>)
+}
+
+pub fn handle_request(req: &KafkaRequest, session: &Mutex<Session>, expired: bool, db: &PgState) -> Handled {
+    let access = session.lock().unwrap().access(db);
+    Handled::Response(match req.req {
+        ApiRequest::Metadata { ref topics } => handle_metadata(&req.header, topics, session, &access, db),
+        ApiRequest::Publish { acks, timeout, ref topics } => handle_publish(&req.header, acks, timeout, topics, &access, db),
+        ApiRequest::Fetch { min_bytes, max_bytes, ref topics, .. } =>
+            return handle_fetch(&req.header, min_bytes, max_bytes, topics, expired, &access, db),
+        ApiRequest::Versions { ref client_software_name, ref client_software_version } =>
+            handle_versions(&req.header, client_software_name, client_software_version, session, db),
+        ApiRequest::FindGroupCoordinator { ref group_id } => handle_find_coordinator(&req.header, group_id, session, &access, db),
+        ApiRequest::JoinGroup { ref group_id, session_timeout, rebalance_timeout, ref member_id, ref protocol_type, ref protocols } =>
+            return handle_join_group(&req.header, group_id, session_timeout, rebalance_timeout, member_id, protocol_type, protocols, session, &access, db),
+        ApiRequest::SyncGroup { ref group_id, generation_id, ref member_id, ref assignments } =>
+            return handle_sync_group(&req.header, group_id, generation_id, member_id, assignments, &access, db),
+        ApiRequest::FetchOffsets { ref group_id, ref topics } => handle_fetch_offsets(&req.header, group_id, topics, &access, db),
+        ApiRequest::Offsets { ref topics } => handle_offsets(&req.header, topics, &access, db),
+        ApiRequest::OffsetCommit { ref group_id, generation_id, ref member_id, retention, ref topics } =>
+            handle_offset_commit(&req.header, group_id, generation_id, member_id, retention, topics, &access, db),
+        ApiRequest::Heartbeat { ref group_id, generation_id, ref member_id } =>
+            handle_heartbeat(&req.header, group_id, generation_id, member_id, &access, db),
+        ApiRequest::LeaveGroup { ref group_id, ref member_id } => handle_leave_group(&req.header, group_id, member_id, &access, db),
+        ApiRequest::CreateTopics { ref topics, validate_only } => handle_create_topics(&req.header, topics, validate_only, &access, db),
+        ApiRequest::DeleteTopics { ref topics } => handle_delete_topics(&req.header, topics, &access, db),
+        ApiRequest::ListGroups => handle_list_groups(&req.header, &access, db),
+        ApiRequest::DescribeGroups { ref groups } => handle_describe_groups(&req.header, groups, &access, db),
+        ApiRequest::SaslHandshake { ref mechanism } => handle_sasl_handshake(&req.header, mechanism, session, db),
+        ApiRequest::SaslAuthenticate { ref auth_bytes } => handle_sasl_authenticate(&req.header, auth_bytes, session, db),
+        ApiRequest::DescribeScramCredentials { ref users } => handle_describe_scram_credentials(&req.header, users, &access, db),
+        ApiRequest::AlterScramCredentials { ref deletions, ref upsertions } =>
+            handle_alter_scram_credentials(&req.header, deletions, upsertions, &access, db),
+        ApiRequest::DescribeAcls { ref filter } => handle_describe_acls(&req.header, filter, &access, db),
+        ApiRequest::CreateAcls { ref creations } => handle_create_acls(&req.header, creations, &access, db),
+        ApiRequest::DeleteAcls { ref filters } => handle_delete_acls(&req.header, filters, &access, db),
+        _ => handle_unknown(req)
+    })
+}
+
+// Before a connection to a SASL listener authenticates only ApiVersions and the SASL exchange are served.
+// After a failed authentication nothing is.
+pub fn authenticated(req: &KafkaRequest, session: &Mutex<Session>, db: &PgState) -> bool {
+    let session = session.lock().unwrap();
+    if session.auth_failed {
+        return false;
+    }
+    if !db.listener(&session.listener).protocol.sasl() || session.principal.is_some() {
+        return true;
+    }
     match req.req {
-        ApiRequest::Metadata { topics } => handle_metadata(&req.header, &topics, db),
-        ApiRequest::Publish { topics, .. } => handle_publish(&req.header, &topics, db),
-        ApiRequest::Fetch { topics } => handle_fetch(&req.header, &topics, db),
-        ApiRequest::Versions => handle_versions(&req),
-        ApiRequest::FindGroupCoordinator => handle_find_coordinator(&req, db),
-        ApiRequest::JoinGroup { protocols, .. } => handle_join_group(&req.header, &protocols),
-        ApiRequest::SyncGroup { assignments, .. } => handle_sync_group(&req.header, &assignments),
-        ApiRequest::FetchOffsets { topics, .. } => handle_fetch_offsets(&req.header, &topics),
-        ApiRequest::Offsets { topics } => handle_offsets(&req.header, &topics, db),
-        ApiRequest::OffsetCommit { topics } => handle_offset_commit(&req.header, &topics),
-        ApiRequest::Heartbeat => handle_heartbeat(&req),
-        ApiRequest::LeaveGroup => handle_leave_group(&req),
-        _ => handle_unknown(&req)
-    }
-}
-
-fn handle_versions(req: &KafkaRequest) -> KafkaResponse {
+        ApiRequest::Versions { .. } | ApiRequest::SaslHandshake { .. } | ApiRequest::SaslAuthenticate { .. } => true,
+        _ => false
+    }
+}
+
+fn handle_sasl_handshake(header: &KafkaRequestHeader, mechanism: &str, session: &Mutex<Session>, db: &PgState) -> KafkaResponse {
+    let mut session = session.lock().unwrap();
+    let mechanisms = if db.listener(&session.listener).protocol.sasl() { db.sasl_mechanisms.clone() } else { Vec::new() };
+    let error_code = if session.principal.is_some() {
+        ILLEGAL_SASL_STATE
+    } else if mechanisms.iter().any(|m| m == mechanism) {
+        session.mechanism = Some(mechanism.to_string());
+        NONE
+    } else {
+        UNSUPPORTED_SASL_MECHANISM
+    };
     KafkaResponse {
-        header: KafkaResponseHeader::new(req.header.correlation_id),
-        req: ApiResponse::VersionsResponse
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::SaslHandshakeResponse {
+            error_code: error_code,
+            mechanisms: mechanisms
+        }
+    }
+}
+
+fn handle_sasl_authenticate(header: &KafkaRequestHeader, auth_bytes: &[u8], session: &Mutex<Session>, db: &PgState) -> KafkaResponse {
+    let mut session = session.lock().unwrap();
+    // The handshake is good for a single exchange
+    let mechanism = session.mechanism.take();
+    let scram = session.scram.take();
+    let illegal = (ILLEGAL_SASL_STATE, "SaslAuthenticate is only expected right after a SaslHandshake".to_string());
+    // The authenticated user when the exchange is complete and the reply to the client
+    let result = match (mechanism, scram) {
+        _ if session.principal.is_some() => Err(illegal),
+        (Some(ref m), None) if m == auth::PLAIN => sasl_plain(auth_bytes, db).map(|user| (Some(user), Vec::new())),
+        (Some(m), None) => match Scram::from_name(&m) {
+            Some(mechanism) => match scram_first(mechanism, auth_bytes, db) {
+                Ok((exchange, reply)) => {
+                    session.mechanism = Some(m.to_string());
+                    session.scram = Some(exchange);
+                    Ok((None, reply))
+                },
+                Err(e) => Err(e)
+            },
+            None => Err(illegal)
+        },
+        (Some(_), Some(exchange)) => {
+            let user = exchange.user.to_string();
+            exchange.finish(auth_bytes).map(|reply| (Some(user), reply)).map_err(|e| (SASL_AUTHENTICATION_FAILED, e))
+        },
+        (None, _) => Err(illegal)
+    };
+    let (error_code, error_message, reply) = match result {
+        Ok((Some(user), reply)) => {
+            info!("Client {} at {} authenticated as {}", header.client_id, session.peer, user);
+            session.principal = Some(auth::principal(&user));
+            (NONE, None, reply)
+        },
+        Ok((None, reply)) => (NONE, None, reply),
+        Err((error_code, message)) => {
+            warn!("Client {} at {} failed to authenticate: {}", header.client_id, session.peer, message);
+            session.auth_failed = error_code == SASL_AUTHENTICATION_FAILED;
+            (error_code, Some(message), Vec::new())
+        }
+    };
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::SaslAuthenticateResponse {
+            version: header.version,
+            error_code: error_code,
+            error_message: error_message,
+            auth_bytes: reply
+        }
+    }
+}
+
+fn sasl_plain(auth_bytes: &[u8], db: &PgState) -> Result<String, (u16, String)> {
+    let failed = (SASL_AUTHENTICATION_FAILED, "Authentication failed: Invalid username or password".to_string());
+    match db.users {
+        Some(ref users) => users.plain(auth_bytes).ok_or(failed),
+        None => Err(failed)
+    }
+}
+
+fn scram_first(mechanism: Scram, auth_bytes: &[u8], db: &PgState) -> Result<(ScramExchange, Vec<u8>), (u16, String)> {
+    let first = ScramClientFirst::parse(auth_bytes).map_err(|e| (SASL_AUTHENTICATION_FAILED, e))?;
+    match scram_credential(&first.user, mechanism, db) {
+        Some(credential) => Ok(first.respond(mechanism, credential)),
+        None => Err((SASL_AUTHENTICATION_FAILED, "Authentication failed: Invalid user credentials".to_string()))
+    }
+}
+
+fn scram_credential(user: &str, mechanism: Scram, db: &PgState) -> Option<ScramCredential> {
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let rs = conn.query(r#"SELECT salt, stored_key, server_key, iterations FROM "__scram_credentials" WHERE name = $1 AND mechanism = $2"#,
+        &[&user, &(mechanism.type_id() as i16)]).expect("Failed to read from the DB");
+    rs.iter().next().map(|row| ScramCredential {
+        salt: row.get(0),
+        stored_key: row.get(1),
+        server_key: row.get(2),
+        iterations: row.get(3)
+    })
+}
+
+fn handle_describe_scram_credentials(header: &KafkaRequestHeader, users: &Option<Vec<String>>, access: &Access, db: &PgState) -> KafkaResponse {
+    if !access.allows(acl::DESCRIBE, acl::CLUSTER, acl::CLUSTER_NAME) {
+        return KafkaResponse {
+            header: KafkaResponseHeader::new(header.correlation_id),
+            req: ApiResponse::DescribeScramCredentialsResponse {
+                error_code: CLUSTER_AUTHORIZATION_FAILED,
+                results: Vec::new()
+            }
+        };
+    }
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let mut credentials: Vec<(String, Vec<(i8, i32)>)> = Vec::new();
+    for row in &conn.query(r#"SELECT name, mechanism, iterations FROM "__scram_credentials" ORDER BY name, mechanism"#, &[])
+            .expect("Failed to read from the DB") {
+        let name: String = row.get(0);
+        let mechanism: i16 = row.get(1);
+        let iterations: i32 = row.get(2);
+        if credentials.last().map(|c| c.0 != name).unwrap_or(true) {
+            credentials.push((name, Vec::new()));
+        }
+        credentials.last_mut().unwrap().1.push((mechanism as i8, iterations));
+    }
+    let results = match *users {
+        None => credentials.into_iter().map(|(name, c)| (name, NONE, None, c)).collect(),
+        Some(ref users) => users.iter().map(|user| {
+            if users.iter().filter(|u| *u == user).count() > 1 {
+                (user.to_string(), DUPLICATE_RESOURCE, Some("Cannot describe SCRAM credentials for the same user twice".to_string()), Vec::new())
+            } else {
+                match credentials.iter().find(|c| &c.0 == user) {
+                    Some(c) => (user.to_string(), NONE, None, c.1.clone()),
+                    None => (user.to_string(), RESOURCE_NOT_FOUND, Some("The user has no SCRAM credentials".to_string()), Vec::new())
+                }
+            }
+        }).collect()
+    };
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::DescribeScramCredentialsResponse {
+            error_code: NONE,
+            results: results
+        }
+    }
+}
+
+// Every user may appear once in the request, either deleted or upserted
+fn handle_alter_scram_credentials(header: &KafkaRequestHeader, deletions: &Vec<(String, i8)>, upsertions: &Vec<ScramUpsertion>,
+                                  access: &Access, db: &PgState) -> KafkaResponse {
+    let names: Vec<&String> = deletions.iter().map(|d| &d.0).chain(upsertions.iter().map(|u| &u.name)).collect();
+    if !access.allows(acl::ALTER, acl::CLUSTER, acl::CLUSTER_NAME) {
+        return KafkaResponse {
+            header: KafkaResponseHeader::new(header.correlation_id),
+            req: ApiResponse::AlterScramCredentialsResponse {
+                results: names.iter()
+                    .map(|name| alter_result(name, Err((CLUSTER_AUTHORIZATION_FAILED, "Not authorized to alter the credentials"))))
+                    .collect()
+            }
+        };
+    }
+    let duplicate = |name: &String| names.iter().filter(|n| **n == name).count() > 1;
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let mut results: Vec<(String, u16, Option<String>)> = Vec::new();
+    for &(ref name, mechanism) in deletions {
+        let result = match Scram::from_type(mechanism) {
+            _ if duplicate(name) => Err((DUPLICATE_RESOURCE, "A user credential cannot be altered twice in the same request")),
+            _ if name.is_empty() => Err((UNACCEPTABLE_CREDENTIAL, "Username must not be empty")),
+            None => Err((UNSUPPORTED_SASL_MECHANISM, "Unknown SCRAM mechanism")),
+            Some(mechanism) => {
+                let deleted = conn.execute(r#"DELETE FROM "__scram_credentials" WHERE name = $1 AND mechanism = $2"#,
+                    &[name, &(mechanism.type_id() as i16)]).expect("Failed to delete from the DB");
+                if deleted == 0 {
+                    Err((RESOURCE_NOT_FOUND, "Attempt to delete a user credential that does not exist"))
+                } else {
+                    info!("Deleted the {} credentials of {}", mechanism.name(), name);
+                    Ok(())
+                }
+            }
+        };
+        results.push(alter_result(name, result));
+    }
+    for u in upsertions {
+        let result = match Scram::from_type(u.mechanism) {
+            _ if duplicate(&u.name) => Err((DUPLICATE_RESOURCE, "A user credential cannot be altered twice in the same request")),
+            _ if u.name.is_empty() => Err((UNACCEPTABLE_CREDENTIAL, "Username must not be empty")),
+            None => Err((UNSUPPORTED_SASL_MECHANISM, "Unknown SCRAM mechanism")),
+            Some(_) if u.iterations < auth::SCRAM_MIN_ITERATIONS || u.iterations > auth::SCRAM_MAX_ITERATIONS =>
+                Err((UNACCEPTABLE_CREDENTIAL, "Iterations must be between 4096 and 16384")),
+            Some(_) if u.salt.is_empty() || u.salted_password.is_empty() =>
+                Err((UNACCEPTABLE_CREDENTIAL, "Salt and salted password must not be empty")),
+            Some(mechanism) => {
+                let credential = mechanism.credential(&u.salted_password, &u.salt, u.iterations);
+                conn.execute(r#"INSERT INTO "__scram_credentials" (name, mechanism, salt, stored_key, server_key, iterations, updated_ts)
+                                VALUES ($1, $2, $3, $4, $5, $6, now())
+                                ON CONFLICT (name, mechanism) DO UPDATE
+                                SET salt = $3, stored_key = $4, server_key = $5, iterations = $6, updated_ts = now()"#,
+                    &[&u.name, &(mechanism.type_id() as i16), &credential.salt, &credential.stored_key, &credential.server_key, &credential.iterations])
+                    .expect("Failed to write to the DB");
+                info!("Updated the {} credentials of {}", mechanism.name(), u.name);
+                Ok(())
+            }
+        };
+        results.push(alter_result(&u.name, result));
+    }
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::AlterScramCredentialsResponse {
+            results: results
+        }
+    }
+}
+
+fn alter_result(name: &str, result: Result<(), (u16, &str)>) -> (String, u16, Option<String>) {
+    match result {
+        Ok(()) => (name.to_string(), NONE, None),
+        Err((error_code, message)) => (name.to_string(), error_code, Some(message.to_string()))
+    }
+}
+
+// The ACL requests need an authorizer, and Describe or Alter on the cluster
+fn acl_request_error(operation: i8, access: &Access, db: &PgState) -> Option<(u16, String)> {
+    if !db.authorizer.enabled() {
+        Some((SECURITY_DISABLED, "No authorizer is configured, set authorizer.enable".to_string()))
+    } else if !access.allows(operation, acl::CLUSTER, acl::CLUSTER_NAME) {
+        Some((CLUSTER_AUTHORIZATION_FAILED, "Not authorized to manage the ACLs".to_string()))
+    } else {
+        None
+    }
+}
+
+fn handle_describe_acls(header: &KafkaRequestHeader, filter: &AclFilter, access: &Access, db: &PgState) -> KafkaResponse {
+    let (error_code, error_message, acls) = match acl_request_error(acl::DESCRIBE, access, db) {
+        Some((error_code, message)) => (error_code, Some(message), Vec::new()),
+        None => (NONE, None, db.authorizer.find(filter))
+    };
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::DescribeAclsResponse {
+            version: header.version,
+            error_code: error_code,
+            error_message: error_message,
+            acls: acls
+        }
+    }
+}
+
+fn handle_create_acls(header: &KafkaRequestHeader, creations: &Vec<AclBinding>, access: &Access, db: &PgState) -> KafkaResponse {
+    let request_error = acl_request_error(acl::ALTER, access, db);
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let results = creations.iter().map(|creation| {
+        if let Some((error_code, ref message)) = request_error {
+            return (error_code, Some(message.to_string()));
+        }
+        if let Err(message) = acl::validate(creation) {
+            return (INVALID_REQUEST, Some(message));
+        }
+        conn.execute(r#"INSERT INTO "__acls" (resource_type, resource_name, pattern_type, principal, host, operation, permission_type, created_ts)
+                        VALUES ($1, $2, $3, $4, $5, $6, $7, now()) ON CONFLICT DO NOTHING"#,
+            &[&(creation.resource_type as i16), &creation.resource_name, &(creation.pattern_type as i16), &creation.principal,
+              &creation.host, &(creation.operation as i16), &(creation.permission_type as i16)]).expect("Failed to write to the DB");
+        if db.authorizer.add(creation.clone()) {
+            info!("Added ACL {:?}", creation);
+        }
+        (NONE, None)
+    }).collect();
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::CreateAclsResponse {
+            results: results
+        }
+    }
+}
+
+fn handle_delete_acls(header: &KafkaRequestHeader, filters: &Vec<AclFilter>, access: &Access, db: &PgState) -> KafkaResponse {
+    let request_error = acl_request_error(acl::ALTER, access, db);
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let results = filters.iter().map(|filter| {
+        if let Some((error_code, ref message)) = request_error {
+            return (error_code, Some(message.to_string()), Vec::new());
+        }
+        let deleted = db.authorizer.find(filter);
+        for acl in &deleted {
+            conn.execute(r#"DELETE FROM "__acls" WHERE resource_type = $1 AND resource_name = $2 AND pattern_type = $3
+                            AND principal = $4 AND host = $5 AND operation = $6 AND permission_type = $7"#,
+                &[&(acl.resource_type as i16), &acl.resource_name, &(acl.pattern_type as i16), &acl.principal,
+                  &acl.host, &(acl.operation as i16), &(acl.permission_type as i16)]).expect("Failed to delete from the DB");
+            db.authorizer.remove(acl);
+            info!("Deleted ACL {:?}", acl);
+        }
+        (NONE, None, deleted)
+    }).collect();
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::DeleteAclsResponse {
+            version: header.version,
+            results: results
+        }
+    }
+}
+
+// Just for the operators to know, a failure here must not fail the ApiVersions of the client
+fn record_client_software(client_id: &str, name: &str, version: &str, peer: &str, db: &PgState) {
+    let result = db.pool.get().map_err(|e| e.to_string()).and_then(|conn|
+        conn.execute(r#"INSERT INTO "__client_software" (client_id, software_name, software_version, address, first_seen_ts, last_seen_ts)
+                        VALUES ($1, $2, $3, $4, now(), now())
+                        ON CONFLICT (client_id, software_name, software_version, address) DO UPDATE SET last_seen_ts=now()"#,
+            &[&client_id, &name, &version, &peer]).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("Failed to record the client software of {} at {}: {}", client_id, peer, e);
+    }
+}
+
+fn handle_versions(header: &KafkaRequestHeader, software_name: &Option<String>, software_version: &Option<String>,
+                   session: &Mutex<Session>, db: &PgState) -> KafkaResponse {
+    if let (&Some(ref name), &Some(ref version)) = (software_name, software_version) {
+        let mut session = session.lock().unwrap();
+        let software = Some((name.to_string(), version.to_string()));
+        // Once per connection, the clients send ApiVersions again when they fall back to an older version
+        if session.client_software != software {
+            info!("Client {} at {} runs {} {}", header.client_id, session.peer, name, version);
+            record_client_software(&header.client_id, name, version, &session.peer, db);
+            session.client_software = software;
+        }
+    }
+    // Newer clients get our versions in the v0 layout and retry with the ones we have
+    let (version, error_code) = if parser::is_supported(header.opcode, header.version) {
+        (header.version, NONE)
+    } else {
+        (0, UNSUPPORTED_VERSION)
+    };
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::VersionsResponse {
+            version: version,
+            error_code: error_code
+        }
     }
 }
 
@@ -83,36 +753,83 @@ fn handle_unknown(req: &KafkaRequest) -> KafkaResponse {
     }
 }
 
-fn handle_metadata(header: &KafkaRequestHeader, topics: &Vec<String>, db: &PgState) -> KafkaResponse {
+// The clients are sent to the advertised endpoint of the listener they came through
+// The topics the client may not describe are reported the same whether they exist or not
+fn handle_metadata(header: &KafkaRequestHeader, topics: &Vec<String>, session: &Mutex<Session>, access: &Access, db: &PgState) -> KafkaResponse {
+    let listener = db.listener(&session.lock().unwrap().listener);
+    let topics: Vec<(String, Result<u32, u16>)> = topics.iter()
+        .map(|t| (t.to_string(), if access.allows(acl::DESCRIBE, acl::TOPIC, t) {
+            db.topic(t).map(|t| t.partitions()).ok_or(UNKNOWN_TOPIC_OR_PARTITION)
+        } else {
+            Err(TOPIC_AUTHORIZATION_FAILED)
+        }))
+        .collect();
     KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
-        req: ApiResponse::metadata_healthy(header.version, topics, &db.hostname)
+        req: ApiResponse::metadata(header.version, &topics, &listener.advertised_host, listener.advertised_port)
     }
 }
 
-fn handle_publish(header: &KafkaRequestHeader, topics: &Vec<KafkaMessageSet>, db: &PgState) -> KafkaResponse {
+fn partition_exists(topic: &str, partition: u32, db: &PgState) -> bool {
+    db.topic(topic).map(|t| partition < t.partitions()).unwrap_or(false)
+}
+
+// The log start offset and the next offset of a partition. The retention cleanup moves the log start,
+// an empty partition starts at its next offset.
+fn partition_offsets(topic: &str, partition: u32, conn: &r2d2::PooledConnection<PostgresConnectionManager>) -> (i64, i64) {
+    let rs = conn.query(format!(r#"SELECT coalesce((SELECT min("offset") FROM "{}" WHERE partition = $2), next_offset), next_offset
+                                   FROM "__partitions" WHERE topic = $1 AND partition = $2"#, topic).as_str(),
+        &[&topic, &(partition as i32)]).expect("DB query failed");
+    rs.iter().next().map(|r| (r.get(0), r.get(1))).unwrap_or((0, 0))
+}
+
+fn handle_publish(header: &KafkaRequestHeader, acks: i16, timeout: u32, topics: &Vec<KafkaMessageSet>, access: &Access, db: &PgState) -> KafkaResponse {
     let conn = db.pool.get().expect("Could not get a DB connection");
-    let mut responses: Vec<(String, Vec<u32>)> = Vec::new();
+    let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+    let mut responses: Vec<(String, Vec<(u32, u16, i64, i64)>)> = Vec::new();
     for topic in topics {
-        let mut partition_responses: Vec<u32> = Vec::new();
+        let mut partition_responses: Vec<(u32, u16, i64, i64)> = Vec::new();
+        let log_append_time = db.topic(&topic.topic).map(|t| t.log_append_time()).unwrap_or(false);
+        let authorized = access.allows(acl::WRITE, acl::TOPIC, &topic.topic);
         for partition in &topic.messages {
             let &(ref p_num, ref values) = partition;
-            for msg in values {
-                debug!("Actually saving message {:?}:{:?} to topic {:?} partition {:?}", msg.key, msg.value, topic.topic, p_num);
-                let uniq = if db.topics.get(&topic.topic).and_then(|t| t.compacted).unwrap_or(false) {
-                    "ON CONFLICT (key) DO UPDATE SET value=$3, ts=now()"
-                    // We can also easily update id to the next seq value. But this may have performance implications (index rebuild)
-                } else {
-                    ""
-                };
-                conn.execute(format!("INSERT INTO \"{}\" (partition, ts, key, value) VALUES ($1, now(), $2, $3) {}",
-                    topic.topic, uniq).as_str(),
-                    &[&(*p_num as i32), &msg.key, &msg.value]).expect("Failed to insert to the DB");
+            if !authorized {
+                partition_responses.push((*p_num, TOPIC_AUTHORIZATION_FAILED, -1, -1));
+                continue;
+            }
+            if !partition_exists(&topic.topic, *p_num, db) {
+                partition_responses.push((*p_num, UNKNOWN_TOPIC_OR_PARTITION, -1, -1));
+                continue;
+            }
+            let values = match *values {
+                Some(ref values) => values,
+                None => {
+                    warn!("Rejecting a corrupt message set for topic {} partition {}", topic.topic, p_num);
+                    partition_responses.push((*p_num, CORRUPT_MESSAGE, -1, -1));
+                    continue;
+                }
+            };
+            let timeout = match write_timeout(acks, deadline, Instant::now()) {
+                Ok(timeout) => timeout,
+                Err(error_code) => {
+                    partition_responses.push((*p_num, error_code, -1, -1));
+                    continue;
+                }
+            };
+            match publish_partition(&conn, &topic.topic, *p_num, values, timeout, db) {
+                Ok((base_offset, append_time)) =>
+                    partition_responses.push((*p_num, NONE, base_offset, if log_append_time { append_time } else { -1 })),
+                Err(error_code) => partition_responses.push((*p_num, error_code, -1, -1))
             }
-            partition_responses.push(*p_num);
         }
         responses.push((topic.topic.to_string(), partition_responses));
     }
+    if acks == 0 {
+        return KafkaResponse {
+            header: KafkaResponseHeader::new(header.correlation_id),
+            req: ApiResponse::NoResponse
+        };
+    }
     KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::PublishResponse {
@@ -122,96 +839,416 @@ fn handle_publish(header: &KafkaRequestHeader, topics: &Vec<KafkaMessageSet>, db
     }
 }
 
-fn handle_fetch(header: &KafkaRequestHeader, topics: &Vec<(String, Vec<(u32, u64)>)>, db: &PgState) -> KafkaResponse {
-    let conn = db.pool.get().expect("Could not get a DB connection");
-    let mut responses: Vec<(String, Vec<(u64, Option<Vec<u8>>, Vec<u8>)>)> = Vec::new();
-    for topic in topics {
-        let mut partition_responses: Vec<(u64, Option<Vec<u8>>, Vec<u8>)> = Vec::new();
-        let id = topic.1.get(0).expect("Need at least one partion in request").1 as i64;
-        // TODO smart limit calculation
-        let rs = conn.query(format!("SELECT id, partition, key, value FROM \"{}\" WHERE id >= $1 LIMIT 25", topic.0).as_str(), &[&id]).expect("DB query failed");
-        for row in &rs {
-            let offset: i64 = row.get(0);
-            let key: Option<Vec<u8>> = row.get(2);
-            let value: Vec<u8> = row.get(3);
-            partition_responses.push((offset as u64, key, value));
-        }
-        responses.push((topic.0.to_string(), partition_responses));
+// What is left of the producer's timeout for the write of a partition. Like in Kafka it only bounds the wait
+// for the replicas, that is the WAL flush of acks=-1. The other writes take as long as they take.
+fn write_timeout(acks: i16, deadline: Instant, now: Instant) -> Result<Option<Duration>, u16> {
+    if acks != -1 {
+        Ok(None)
+    } else if now >= deadline {
+        Err(REQUEST_TIMED_OUT)
+    } else {
+        Ok(Some(deadline - now))
+    }
+}
+
+// Writes a batch to a partition in a single transaction. Returns the base offset and the append time of the batch.
+fn publish_partition(conn: &r2d2::PooledConnection<PostgresConnectionManager>, topic: &str, p_num: u32, values: &Vec<KafkaMessage>,
+                     timeout: Option<Duration>, db: &PgState) -> Result<(i64, i64), u16> {
+    let log_append_time = db.topic(topic).map(|t| t.log_append_time()).unwrap_or(false);
+    let uniq = if db.topic(topic).and_then(|t| t.compacted).unwrap_or(false) {
+        // The latest value of a key moves to the new offset, so the consumers past the old one see it
+        r#"ON CONFLICT (partition, key) DO UPDATE SET "offset"=EXCLUDED."offset", value=$4, header_keys=$5, header_values=$6, ts=now(), "timestamp"=$7"#
+    } else {
+        ""
+    };
+    let tx = conn.transaction().map_err(write_failed)?;
+    // acks=-1 waits for the WAL flush (and the synchronous replicas, if any), acks=0 and acks=1 only for the local write.
+    // The producer's timeout of acks=-1 bounds every statement of the transaction.
+    tx.batch_execute(&match timeout {
+        Some(timeout) => format!("SET LOCAL synchronous_commit TO on; SET LOCAL statement_timeout TO {}",
+            timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000 + 1),
+        None => "SET LOCAL synchronous_commit TO off".to_string()
+    }).map_err(write_failed)?;
+    // Reserve the offsets for the whole batch. The row lock serializes the producers of a partition.
+    let rs = tx.query(r#"UPDATE "__partitions" SET next_offset = next_offset + $3
+                         WHERE topic = $1 AND partition = $2 RETURNING next_offset - $3"#,
+        &[&topic, &(p_num as i32), &(values.len() as i64)]).map_err(write_failed)?;
+    let base_offset: i64 = rs.get(0).get(0);
+    // The time of the write in milliseconds, the same for the whole batch
+    let rs = tx.query(r#"SELECT (extract(epoch FROM now()) * 1000)::bigint"#, &[]).map_err(write_failed)?;
+    let append_time: i64 = rs.get(0).get(0);
+    for (i, msg) in values.iter().enumerate() {
+        debug!("Actually saving message {:?}:{:?} to topic {:?} partition {:?}", msg.key, msg.value, topic, p_num);
+        let (header_keys, header_values): (Vec<String>, Vec<Option<Vec<u8>>>) = msg.headers.iter().cloned().unzip();
+        let (header_keys, header_values) = if msg.headers.is_empty() { (None, None) } else { (Some(header_keys), Some(header_values)) };
+        let timestamp = if log_append_time { append_time } else { msg.timestamp };
+        tx.execute(format!(r#"INSERT INTO "{}" (partition, "offset", ts, key, value, header_keys, header_values, "timestamp")
+                              VALUES ($1, $2, now(), $3, $4, $5, $6, $7) {}"#,
+            topic, uniq).as_str(),
+            &[&(p_num as i32), &(base_offset + i as i64), &msg.key, &msg.value, &header_keys, &header_values, &timestamp])
+            .map_err(write_failed)?;
+    }
+    // Delivered on commit, wakes up the fetches parked on the partition
+    tx.execute("SELECT pg_notify($1, $2)", &[&notifier::CHANNEL, &format!("{}:{}", topic, p_num)]).map_err(write_failed)?;
+    tx.commit().map_err(write_failed)?;
+    Ok((base_offset, append_time))
+}
+
+// A statement cancelled by the statement_timeout is a timeout, anything else a storage error the producer may retry.
+// The transaction is rolled back when dropped.
+fn write_failed(e: postgres::Error) -> u16 {
+    if e.code() == Some(&postgres::error::QUERY_CANCELED) {
+        REQUEST_TIMED_OUT
+    } else {
+        error!("Failed to write to the DB: {}", e);
+        KAFKA_STORAGE_ERROR
+    }
+}
+
+fn handle_fetch(header: &KafkaRequestHeader, min_bytes: u32, max_bytes: u32, topics: &Vec<(String, Vec<(u32, u64, u32)>)>,
+                expired: bool, access: &Access, db: &PgState) -> Handled {
+    let denied: Vec<&String> = topics.iter().map(|t| &t.0).filter(|t| !access.allows(acl::READ, acl::TOPIC, t)).collect();
+    let since = db.notifier.sequence();
+    let (responses, size) = fetch_partitions(header.version, max_bytes, topics, &denied, db);
+    let error = responses.iter().any(|t| t.1.iter().any(|p| p.error_code != NONE));
+    // Park the fetch until there is enough data, an error to report or the max wait is over
+    if !expired && !error && size < min_bytes as usize {
+        let partitions: Vec<(String, u32)> = topics.iter().flat_map(|t| t.1.iter().map(move |p| (t.0.to_string(), p.0))).collect();
+        return Handled::Parked(db.notifier.park(since, partitions), None);
     }
     debug!("About to send a fetch response with content {:?}", responses);
-    KafkaResponse {
+    Handled::Response(KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::FetchResponse {
             version: header.version,
             responses: responses
         }
+    })
+}
+
+// Rows read from a partition at a time while filling the response
+const FETCH_CHUNK: i64 = 100;
+
+// Also returns the size of all the records in the response
+fn fetch_partitions(version: i16, max_bytes: u32, topics: &Vec<(String, Vec<(u32, u64, u32)>)>, denied: &Vec<&String>, db: &PgState)
+                    -> (Vec<(String, Vec<FetchPartition>)>, usize) {
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let mut responses: Vec<(String, Vec<FetchPartition>)> = Vec::new();
+    let mut response_bytes: usize = 0;
+    for topic in topics {
+        let mut partition_responses: Vec<FetchPartition> = Vec::new();
+        let compression = db.topic(&topic.0).map(|t| t.compression()).unwrap_or(Compression::None);
+        let log_append_time = db.topic(&topic.0).map(|t| t.log_append_time()).unwrap_or(false);
+        for &(partition, offset, partition_max_bytes) in &topic.1 {
+            let mut records: Vec<Record> = Vec::new();
+            if denied.contains(&&topic.0) {
+                partition_responses.push(FetchPartition { partition: partition, error_code: TOPIC_AUTHORIZATION_FAILED, compression: compression,
+                                                         log_append_time: log_append_time, high_watermark: -1, log_start_offset: -1, records: records });
+                continue;
+            }
+            if !partition_exists(&topic.0, partition, db) {
+                partition_responses.push(FetchPartition { partition: partition, error_code: UNKNOWN_TOPIC_OR_PARTITION, compression: compression,
+                                                         log_append_time: log_append_time, high_watermark: -1, log_start_offset: -1, records: records });
+                continue;
+            }
+            let (log_start_offset, next_offset) = partition_offsets(&topic.0, partition, &conn);
+            if (offset as i64) < log_start_offset || (offset as i64) > next_offset {
+                partition_responses.push(FetchPartition { partition: partition, error_code: OFFSET_OUT_OF_RANGE, compression: compression,
+                                                         log_append_time: log_append_time, high_watermark: next_offset,
+                                                      log_start_offset: log_start_offset, records: records });
+                continue;
+            }
+            // Nothing past the high watermark read above, the producers may have committed more since.
+            // Fill the partition up to its max bytes and what is left of the response max bytes.
+            // The very first record of the response goes in whatever its size, so the consumer always makes progress.
+            let budget = ::std::cmp::min(partition_max_bytes as usize, (max_bytes as usize).saturating_sub(response_bytes));
+            let overhead = records_overhead(version, compression); // comes with the first record
+            let mut partition_bytes: usize = 0;
+            let mut next = offset as i64;
+            'chunks: loop {
+                let rs = conn.query(format!(r#"SELECT "offset", key, value, header_keys, header_values, "timestamp" FROM "{}"
+                                               WHERE partition = $1 AND "offset" >= $2 AND "offset" < $3 ORDER BY "offset" LIMIT $4"#, topic.0).as_str(),
+                    &[&(partition as i32), &next, &next_offset, &FETCH_CHUNK]).expect("DB query failed");
+                for row in &rs {
+                    let offset: i64 = row.get(0);
+                    let header_keys: Option<Vec<String>> = row.get(3);
+                    let header_values: Option<Vec<Option<Vec<u8>>>> = row.get(4);
+                    let record = Record {
+                        offset: offset as u64,
+                        timestamp: row.get(5),
+                        key: row.get(1),
+                        value: row.get(2),
+                        headers: header_keys.unwrap_or_default().into_iter().zip(header_values.unwrap_or_default()).collect()
+                    };
+                    let size = record.size(version, records.first().unwrap_or(&record)) + if records.is_empty() { overhead } else { 0 };
+                    if partition_bytes + size > budget && response_bytes + partition_bytes > 0 {
+                        break 'chunks;
+                    }
+                    partition_bytes += size;
+                    records.push(record);
+                    next = offset + 1;
+                }
+                if (rs.len() as i64) < FETCH_CHUNK {
+                    break;
+                }
+            }
+            let mut fetched = FetchPartition { partition: partition, error_code: NONE, compression: compression,
+                                               log_append_time: log_append_time, high_watermark: next_offset,
+                                               log_start_offset: log_start_offset, records: records };
+            if compression != Compression::None && !fetched.records.is_empty() {
+                // The codec may add a few bytes to records that do not compress, measure what goes on the wire
+                partition_bytes = records_size(version, &fetched);
+                while partition_bytes > budget && fetched.records.len() > if response_bytes == 0 { 1 } else { 0 } {
+                    fetched.records.pop();
+                    partition_bytes = records_size(version, &fetched);
+                }
+            }
+            response_bytes += partition_bytes;
+            partition_responses.push(fetched);
+        }
+        responses.push((topic.0.to_string(), partition_responses));
     }
+    (responses, response_bytes)
 }
 
-fn handle_find_coordinator(req: &KafkaRequest, db: &PgState) -> KafkaResponse {
+fn handle_find_coordinator(header: &KafkaRequestHeader, group_id: &str, session: &Mutex<Session>, access: &Access, db: &PgState) -> KafkaResponse {
+    let listener = db.listener(&session.lock().unwrap().listener);
     KafkaResponse {
-        header: KafkaResponseHeader::new(req.header.correlation_id),
+        header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::GroupCoordinatorResponse {
-            hostname: db.hostname.to_string()
+            error_code: if access.allows(acl::DESCRIBE, acl::GROUP, group_id) { NONE } else { GROUP_AUTHORIZATION_FAILED },
+            hostname: listener.advertised_host.to_string(),
+            port: listener.advertised_port
         }
     }
 }
 
-fn handle_join_group(header: &KafkaRequestHeader, protocols: &Vec<(String, Option<Vec<u8>>)>) -> KafkaResponse {
-    let selected = protocols.get(0).cloned();
-    KafkaResponse {
+fn handle_join_group(header: &KafkaRequestHeader, group_id: &str, session_timeout: u32, rebalance_timeout: u32, member_id: &str,
+                     protocol_type: &str, protocols: &Vec<(String, Option<Vec<u8>>)>, session: &Mutex<Session>, access: &Access,
+                     db: &PgState) -> Handled {
+    if !access.allows(acl::READ, acl::GROUP, group_id) {
+        return Handled::Response(KafkaResponse {
+            header: KafkaResponseHeader::new(header.correlation_id),
+            req: ApiResponse::JoinGroupResponse {
+                error_code: GROUP_AUTHORIZATION_FAILED,
+                generation_id: -1,
+                protocol: None,
+                leader_id: String::new(),
+                member_id: member_id.to_string(),
+                members: Vec::new()
+            }
+        });
+    }
+    let (client_host, parked) = {
+        let mut session = session.lock().unwrap();
+        (session.client_host(), session.joining.take())
+    };
+    // A parked join only asks for the outcome, its member is in the group already
+    let joining = match parked {
+        Some(id) => db.coordinator.poll_join(group_id, &id),
+        None => db.coordinator.join(group_id, member_id, &header.client_id, &client_host, session_timeout, rebalance_timeout,
+                                    protocol_type, protocols)
+    };
+    let joined = match joining {
+        Joining::Joined(joined) => joined,
+        Joining::Waiting(id, woken, until) => {
+            session.lock().unwrap().joining = Some(id);
+            return Handled::Parked(woken, Some(until));
+        }
+    };
+    Handled::Response(KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::JoinGroupResponse {
-            protocol: selected
+            error_code: joined.error_code,
+            generation_id: joined.generation_id,
+            protocol: joined.protocol,
+            leader_id: joined.leader_id,
+            member_id: joined.member_id,
+            members: joined.members
+        }
+    })
+}
+
+// The groups with members in the coordinator and the ones that only have committed offsets.
+// Describe on the cluster lists them all, otherwise only the ones the client may describe.
+fn handle_list_groups(header: &KafkaRequestHeader, access: &Access, db: &PgState) -> KafkaResponse {
+    let mut groups = db.coordinator.list();
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    for row in &conn.query(r#"SELECT DISTINCT group_id FROM "__consumer_offsets""#, &[]).expect("Failed to read from the DB") {
+        let group_id: String = row.get(0);
+        if !groups.iter().any(|g| g.0 == group_id) {
+            groups.push((group_id, String::new()));
+        }
+    }
+    if !access.allows(acl::DESCRIBE, acl::CLUSTER, acl::CLUSTER_NAME) {
+        groups.retain(|g| access.allows(acl::DESCRIBE, acl::GROUP, &g.0));
+    }
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::ListGroupsResponse {
+            version: header.version,
+            groups: groups
         }
     }
 }
 
-fn handle_sync_group(header: &KafkaRequestHeader, assignments: &Vec<Option<Vec<u8>>>) -> KafkaResponse {
-    let selected = assignments.get(0).cloned().unwrap_or(None);
+fn handle_describe_groups(header: &KafkaRequestHeader, groups: &Vec<String>, access: &Access, db: &PgState) -> KafkaResponse {
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let descriptions = groups.iter().map(|group_id| {
+        let empty = |error_code: u16, state: &str| GroupDescription {
+            error_code: error_code,
+            group_id: group_id.to_string(),
+            state: state.to_string(),
+            protocol_type: String::new(),
+            protocol: String::new(),
+            members: Vec::new()
+        };
+        if !access.allows(acl::DESCRIBE, acl::GROUP, group_id) {
+            return empty(GROUP_AUTHORIZATION_FAILED, "");
+        }
+        db.coordinator.describe(group_id).unwrap_or_else(|| {
+            // Kafka keeps the groups with committed offsets around as empty ones
+            let rs = conn.query(r#"SELECT 1 FROM "__consumer_offsets" WHERE group_id = $1 LIMIT 1"#, &[group_id])
+                .expect("Failed to read from the DB");
+            empty(NONE, if rs.is_empty() { "Dead" } else { "Empty" })
+        })
+    }).collect();
     KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::DescribeGroupsResponse {
+            version: header.version,
+            groups: descriptions
+        }
+    }
+}
+
+fn handle_sync_group(header: &KafkaRequestHeader, group_id: &str, generation_id: i32, member_id: &str,
+                     assignments: &Vec<(String, Option<Vec<u8>>)>, access: &Access, db: &PgState) -> Handled {
+    let (error_code, assignment) = if access.allows(acl::READ, acl::GROUP, group_id) {
+        match db.coordinator.sync(group_id, generation_id, member_id, assignments) {
+            Syncing::Synced(error_code, assignment) => (error_code, assignment),
+            Syncing::Waiting(woken, until) => return Handled::Parked(woken, Some(until))
+        }
+    } else {
+        (GROUP_AUTHORIZATION_FAILED, None)
+    };
+    Handled::Response(KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::SyncGroupResponse {
-            assignment: selected
+            error_code: error_code,
+            assignment: assignment
+        }
+    })
+}
+
+// Every offset the group committed, of the topics the client may describe
+fn committed_offsets(group_id: &str, access: &Access, conn: &r2d2::PooledConnection<PostgresConnectionManager>)
+                     -> Vec<(String, Vec<(u32, i64, Option<String>, u16)>)> {
+    let rs = conn.query(r#"SELECT topic, partition, "offset", metadata FROM "__consumer_offsets"
+                           WHERE group_id = $1 AND expire_ts > now() ORDER BY topic, partition"#,
+                        &[&group_id]).expect("DB query failed");
+    let mut responses: Vec<(String, Vec<(u32, i64, Option<String>, u16)>)> = Vec::new();
+    let mut denied: Option<String> = None;
+    for row in &rs {
+        let topic: String = row.get(0);
+        let partition: i32 = row.get(1);
+        let offset = (partition as u32, row.get(2), row.get(3), NONE);
+        if denied.as_ref() == Some(&topic) {
+            continue;
+        }
+        if responses.last().map(|t| t.0 == topic).unwrap_or(false) {
+            responses.last_mut().unwrap().1.push(offset);
+        } else if access.allows(acl::DESCRIBE, acl::TOPIC, &topic) {
+            responses.push((topic, vec![offset]));
+        } else {
+            denied = Some(topic);
         }
     }
+    responses
 }
 
-fn handle_fetch_offsets(header: &KafkaRequestHeader, topics: &Vec<TopicWithPartitions>) -> KafkaResponse {
-    let mut responses: Vec<(String, Vec<(u32, i64)>)> = Vec::new();
+fn handle_fetch_offsets(header: &KafkaRequestHeader, group_id: &str, topics: &Option<Vec<TopicWithPartitions>>, access: &Access, db: &PgState) -> KafkaResponse {
+    let error_code = if access.allows(acl::DESCRIBE, acl::GROUP, group_id) { NONE } else { GROUP_AUTHORIZATION_FAILED };
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    let topics = match *topics {
+        Some(ref topics) => topics,
+        None => {
+            let responses = if error_code == NONE { committed_offsets(group_id, access, &conn) } else { Vec::new() };
+            debug!("About to send a fetch offsets response with content {:?}", responses);
+            return KafkaResponse {
+                header: KafkaResponseHeader::new(header.correlation_id),
+                req: ApiResponse::FetchOffsetsResponse {
+                    version: header.version,
+                    error_code: error_code,
+                    topics: responses
+                }
+            };
+        }
+    };
+    let mut responses: Vec<(String, Vec<(u32, i64, Option<String>, u16)>)> = Vec::new();
     for topic in topics {
-        let mut partition_responses: Vec<(u32, i64)> = Vec::new();
-        let offset = -1; // Just say there is no offset on the group yet.
+        let mut partition_responses: Vec<(u32, i64, Option<String>, u16)> = Vec::new();
+        let topic_error = if error_code != NONE {
+            error_code
+        } else if !access.allows(acl::DESCRIBE, acl::TOPIC, &topic.name) {
+            TOPIC_AUTHORIZATION_FAILED
+        } else {
+            NONE
+        };
         for p in &topic.partitions {
-            partition_responses.push((*p, offset));
+            if topic_error != NONE {
+                partition_responses.push((*p, -1, None, topic_error));
+                continue;
+            }
+            let rs = conn.query(r#"SELECT "offset", metadata FROM "__consumer_offsets"
+                                   WHERE group_id = $1 AND topic = $2 AND partition = $3 AND expire_ts > now()"#,
+                                &[&group_id, &topic.name, &(*p as i32)]).expect("DB query failed");
+            match rs.iter().next() {
+                Some(row) => partition_responses.push((*p, row.get(0), row.get(1), NONE)),
+                None      => partition_responses.push((*p, -1, Some(String::new()), NONE)) // No offset committed by the group yet
+            }
         }
         responses.push((topic.name.to_string(), partition_responses));
     }
-    debug!("About to send a fetch offsets response with content {:?}", responses);    
+    debug!("About to send a fetch offsets response with content {:?}", responses);
     KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::FetchOffsetsResponse {
+            version: header.version,
+            error_code: error_code,
             topics: responses
         }
     }
 }
 
-fn handle_offsets(header: &KafkaRequestHeader, topics: &Vec<(String, Vec<(u32, i64)>)>, db: &PgState) -> KafkaResponse {
+fn handle_offsets(header: &KafkaRequestHeader, topics: &Vec<(String, Vec<(u32, i64)>)>, access: &Access, db: &PgState) -> KafkaResponse {
     let conn = db.pool.get().expect("Could not get a DB connection");
-    let mut responses: Vec<(String, Vec<(u32, i64)>)> = Vec::new();
+    let mut responses: Vec<(String, Vec<(u32, u16, i64, i64)>)> = Vec::new();
     for topic in topics {
-        let mut partition_responses: Vec<(u32, i64)> = Vec::new();
-        // Get offset by timestamp. Consider the two special values
-        let offset: i64 = match topic.1.get(0).map(|t| t.1).unwrap_or(-1) {
-            -2 => 0, // Start from the beginning
-            -1 => {  // Start from the current HEAD
-                let rs = conn.query(format!("SELECT max(id) + 1 FROM \"{}\"", topic.0).as_str(), &[]).expect("DB query failed");
-                rs.iter().next().and_then(|r| r.get_opt(0)).unwrap_or(Ok(-1)).unwrap_or(-1)
-            },
-            _ => -1 // TODO Support lookup by an actual timestamp
-        };
-        for p in &topic.1 {
-            partition_responses.push((p.0, offset));
+        let mut partition_responses: Vec<(u32, u16, i64, i64)> = Vec::new();
+        let authorized = access.allows(acl::DESCRIBE, acl::TOPIC, &topic.0);
+        for &(partition, timestamp) in &topic.1 {
+            if !authorized {
+                partition_responses.push((partition, TOPIC_AUTHORIZATION_FAILED, -1, -1));
+                continue;
+            }
+            if !partition_exists(&topic.0, partition, db) {
+                partition_responses.push((partition, UNKNOWN_TOPIC_OR_PARTITION, -1, -1));
+                continue;
+            }
+            // Get offset by timestamp. Consider the two special values
+            let (timestamp, offset): (i64, i64) = match timestamp {
+                -2 => (-1, partition_offsets(&topic.0, partition, &conn).0), // Start from the beginning
+                -1 => (-1, partition_offsets(&topic.0, partition, &conn).1), // Start from the current HEAD
+                _ => {   // The earliest message at or after the timestamp. The (partition, timestamp) index serves the order,
+                         // it is the offset order as long as the timestamps grow with the offsets.
+                    let rs = conn.query(format!(r#"SELECT "timestamp", "offset" FROM "{}"
+                                                   WHERE partition = $1 AND "timestamp" >= $2 ORDER BY "timestamp", "offset" LIMIT 1"#, topic.0).as_str(),
+                        &[&(partition as i32), &timestamp]).expect("DB query failed");
+                    rs.iter().next().map(|r| (r.get(0), r.get(1))).unwrap_or((-1, -1))
+                }
+            };
+            partition_responses.push((partition, NONE, timestamp, offset));
         }
         responses.push((topic.0.to_string(), partition_responses));
     }
@@ -220,38 +1257,265 @@ fn handle_offsets(header: &KafkaRequestHeader, topics: &Vec<(String, Vec<(u32, i
     KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::OffsetsResponse {
+            version: header.version,
             topics: responses
         }
     }
 }
 
-fn handle_offset_commit(header: &KafkaRequestHeader, topics: &Vec<TopicWithPartitions>) -> KafkaResponse {
+// Read on the group and on every topic, the other topics are committed without the denied ones
+fn handle_offset_commit(header: &KafkaRequestHeader, group_id: &str, generation_id: i32, member_id: &str, retention: i64,
+                        topics: &Vec<(String, Vec<(u32, i64, Option<String>)>)>, access: &Access, db: &PgState) -> KafkaResponse {
+    let error_code = if access.allows(acl::READ, acl::GROUP, group_id) {
+        db.coordinator.validate_commit(group_id, generation_id, member_id)
+    } else {
+        GROUP_AUTHORIZATION_FAILED
+    };
+    let mut responses: Vec<(String, Vec<(u32, u16)>)> = Vec::new();
+    // -1 means the client is happy with whatever the server default is
+    let retention = if retention < 0 { db.offsets_retention as i64 } else { retention };
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    for topic in topics {
+        let topic_error = if error_code == NONE && !access.allows(acl::READ, acl::TOPIC, &topic.0) { TOPIC_AUTHORIZATION_FAILED } else { error_code };
+        for &(partition, offset, ref metadata) in &topic.1 {
+            if topic_error != NONE {
+                continue;
+            }
+            debug!("Committing offset {} for group {} on topic {:?} partition {}", offset, group_id, topic.0, partition);
+            conn.execute(r#"INSERT INTO "__consumer_offsets" (group_id, topic, partition, "offset", metadata, commit_ts, expire_ts)
+                            VALUES ($1, $2, $3, $4, $5, now(), now() + $6::text::interval)
+                            ON CONFLICT (group_id, topic, partition)
+                            DO UPDATE SET "offset"=$4, metadata=$5, commit_ts=now(), expire_ts=now() + $6::text::interval"#,
+                &[&group_id, &topic.0, &(partition as i32), &offset, metadata, &format!("{}ms", retention)])
+                .expect("Failed to commit the offset to the DB");
+        }
+        responses.push((topic.0.to_string(), topic.1.iter().map(|p| (p.0, topic_error)).collect()));
+    }
     KafkaResponse {
         header: KafkaResponseHeader::new(header.correlation_id),
         req: ApiResponse::OffsetCommitResponse {
-            topics: topics.to_vec()
+            topics: responses
+        }
+    }
+}
+fn handle_heartbeat(header: &KafkaRequestHeader, group_id: &str, generation_id: i32, member_id: &str, access: &Access, db: &PgState) -> KafkaResponse {
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::HeartbeatResponse {
+            error_code: if access.allows(acl::READ, acl::GROUP, group_id) {
+                db.coordinator.heartbeat(group_id, generation_id, member_id)
+            } else {
+                GROUP_AUTHORIZATION_FAILED
+            }
         }
     }
 }
 
-fn handle_heartbeat(req: &KafkaRequest) -> KafkaResponse {
+fn handle_leave_group(header: &KafkaRequestHeader, group_id: &str, member_id: &str, access: &Access, db: &PgState) -> KafkaResponse {
     KafkaResponse {
-        header: KafkaResponseHeader::new(req.header.correlation_id),
-        req: ApiResponse::HeartbeatResponse
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::LeaveGroupResponse {
+            error_code: if access.allows(acl::READ, acl::GROUP, group_id) {
+                db.coordinator.leave(group_id, member_id)
+            } else {
+                GROUP_AUTHORIZATION_FAILED
+            }
+        }
     }
 }
 
-fn handle_leave_group(req: &KafkaRequest) -> KafkaResponse {
+// Create on the cluster allows any topic, Create on the topic only that one
+fn handle_create_topics(header: &KafkaRequestHeader, topics: &Vec<NewTopic>, validate_only: bool, access: &Access, db: &PgState) -> KafkaResponse {
+    let cluster_create = access.allows(acl::CREATE, acl::CLUSTER, acl::CLUSTER_NAME);
+    let mut responses: Vec<(String, u16, Option<String>)> = Vec::new();
+    for new_topic in topics {
+        let result = if !cluster_create && !access.allows(acl::CREATE, acl::TOPIC, &new_topic.name) {
+            Err((TOPIC_AUTHORIZATION_FAILED, format!("Not authorized to create topic {}", new_topic.name)))
+        } else if topics.iter().filter(|t| t.name == new_topic.name).count() > 1 {
+            Err((INVALID_REQUEST, format!("Topic {} is more than once in the request", new_topic.name)))
+        } else {
+            new_topic_settings(new_topic, db).and_then(|topic| if validate_only { Ok(()) } else { create_topic(topic, db) })
+        };
+        match result {
+            Ok(()) => responses.push((new_topic.name.to_string(), NONE, None)),
+            Err((error_code, message)) => {
+                warn!("Could not create topic {}: {}", new_topic.name, message);
+                responses.push((new_topic.name.to_string(), error_code, Some(message)))
+            }
+        }
+    }
     KafkaResponse {
-        header: KafkaResponseHeader::new(req.header.correlation_id),
-        req: ApiResponse::LeaveGroupResponse
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::CreateTopicsResponse {
+            version: header.version,
+            topics: responses
+        }
     }
 }
 
+// Validates the requested topic and turns it into the same settings the configured topics have
+fn new_topic_settings(new_topic: &NewTopic, db: &PgState) -> Result<Topic, (u16, String)> {
+    let name = &new_topic.name;
+    // The name ends up in the table names, Kafka's own rules keep it safe. The "__" prefix is for our tables.
+    if name.is_empty() || name.len() > 249 || name == "." || name == ".." || name.starts_with("__")
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-') {
+        return Err((INVALID_TOPIC_EXCEPTION, format!("Illegal topic name {}", name)));
+    }
+    if db.topic(name).is_some() {
+        return Err((TOPIC_ALREADY_EXISTS, format!("Topic {} already exists", name)));
+    }
+    let partitions = if !new_topic.assignments.is_empty() {
+        if new_topic.partitions != -1 || new_topic.replication_factor != -1 {
+            return Err((INVALID_REQUEST, "Both the assignments and the number of partitions or replication factor are given".to_string()));
+        }
+        new_topic.assignments.len() as i32
+    } else if new_topic.partitions == -1 {
+        1
+    } else {
+        new_topic.partitions
+    };
+    if partitions <= 0 {
+        return Err((INVALID_PARTITIONS, format!("Invalid number of partitions {}", partitions)));
+    }
+    if new_topic.replication_factor != -1 && new_topic.replication_factor != 1 {
+        return Err((INVALID_REPLICATION_FACTOR, "The data is replicated by Postgres, the replication factor can only be 1".to_string()));
+    }
+    let mut topic = Topic {
+        name: name.to_string(),
+        compacted: Some(false),
+        retention: None,
+        partitions: Some(partitions as u32),
+        compression: None,
+        timestamp_type: None
+    };
+    for &(ref config, ref value) in &new_topic.configs {
+        let value = match *value {
+            Some(ref v) => v,
+            None => continue // the default
+        };
+        let invalid = || Err((INVALID_CONFIG, format!("Invalid value {} for {}", value, config)));
+        match config.as_str() {
+            "cleanup.policy" => match value.as_str() {
+                "compact" => topic.compacted = Some(true),
+                "delete" => topic.compacted = Some(false),
+                _ => return invalid()
+            },
+            "retention.ms" => match value.parse::<i64>() {
+                Ok(-1) => topic.retention = None,
+                Ok(ms) if ms >= 0 => topic.retention = Some(ms as u64),
+                _ => return invalid()
+            },
+            "compression.type" => match value.as_str() {
+                "producer" => topic.compression = None, // we do not keep the producer's codec
+                codec => match Compression::from_name(codec) {
+                    Some(_) => topic.compression = Some(codec.to_string()),
+                    None => return invalid()
+                }
+            },
+            "message.timestamp.type" => match value.as_str() {
+                "CreateTime" | "LogAppendTime" => topic.timestamp_type = Some(value.to_string()),
+                _ => return invalid()
+            },
+            _ => warn!("Ignoring config {}={} of topic {}", config, value, name)
+        }
+    }
+    Ok(topic)
+}
+
+// The topic gets into the map once it is in the DB. Of the concurrent creates of a topic the "__topics" key lets one through.
+fn create_topic(topic: Topic, db: &PgState) -> Result<(), (u16, String)> {
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    register_topic(&topic, &conn).map_err(|e| if e.code() == Some(&postgres::error::UNIQUE_VIOLATION) {
+        (TOPIC_ALREADY_EXISTS, format!("Topic {} already exists", topic.name))
+    } else {
+        error!("Failed to create topic {} in the DB: {}", topic.name, e);
+        (UNKNOWN_SERVER_ERROR, format!("Failed to create topic {}", topic.name))
+    })?;
+    info!("Created topic {} with {} partitions", topic.name, topic.partitions());
+    db.topics.write().unwrap().insert(topic.name.to_string(), topic);
+    Ok(())
+}
+
+// All or nothing, a table that fails to create leaves no "__topics" row behind
+fn register_topic(topic: &Topic, conn: &r2d2::PooledConnection<PostgresConnectionManager>) -> postgres::Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(r#"INSERT INTO "__topics" (name, partitions, compacted, retention, compression, timestamp_type, created_ts)
+                  VALUES ($1, $2, $3, $4, $5, $6, now())"#,
+        &[&topic.name, &(topic.partitions() as i32), &topic.compacted.unwrap_or(false), &topic.retention.map(|r| r as i64),
+          &topic.compression, &topic.timestamp_type])?;
+    create_topic_table(topic, &tx)?;
+    tx.commit()
+}
+
+fn handle_delete_topics(header: &KafkaRequestHeader, topics: &Vec<String>, access: &Access, db: &PgState) -> KafkaResponse {
+    let responses: Vec<(String, u16)> = topics.iter()
+        .map(|topic| (topic.to_string(), if !access.allows(acl::DELETE, acl::TOPIC, topic) {
+            TOPIC_AUTHORIZATION_FAILED
+        } else if db.delete_topic_enable {
+            delete_topic(topic, db)
+        } else {
+            TOPIC_DELETION_DISABLED
+        }))
+        .collect();
+    KafkaResponse {
+        header: KafkaResponseHeader::new(header.correlation_id),
+        req: ApiResponse::DeleteTopicsResponse {
+            version: header.version,
+            topics: responses
+        }
+    }
+}
+
+// Drops the topic's table, partitions and committed offsets. The configured topics would be back on restart, they stay.
+// The DROP waits for the produces and fetches of the topic to finish, the other topics go on meanwhile.
+fn delete_topic(name: &str, db: &PgState) -> u16 {
+    if db.topic(name).is_none() {
+        return UNKNOWN_TOPIC_OR_PARTITION;
+    }
+    // Even if CreateTopics made it before it got into the configuration
+    if db.configured_topics.iter().any(|t| t == name) {
+        warn!("Topic {} is in the configuration file, not deleting it", name);
+        return POLICY_VIOLATION;
+    }
+    let conn = db.pool.get().expect("Could not get a DB connection");
+    match drop_topic(name, &conn) {
+        Ok(true) => {
+            let removed = db.topics.write().unwrap().remove(name);
+            // The fetches parked on the topic answer UNKNOWN_TOPIC_OR_PARTITION right away
+            for partition in 0..removed.map(|t| t.partitions()).unwrap_or(0) {
+                db.notifier.notify(name, partition);
+            }
+            info!("Deleted topic {}", name);
+            NONE
+        },
+        Ok(false) => UNKNOWN_TOPIC_OR_PARTITION, // a concurrent delete got it first
+        Err(e) => {
+            error!("Failed to delete topic {} from the DB: {}", name, e);
+            UNKNOWN_SERVER_ERROR
+        }
+    }
+}
+
+// Returns false if the topic is not one of the created ones
+fn drop_topic(name: &str, conn: &r2d2::PooledConnection<PostgresConnectionManager>) -> postgres::Result<bool> {
+    let tx = conn.transaction()?;
+    if tx.execute(r#"DELETE FROM "__topics" WHERE name = $1"#, &[&name])? == 0 {
+        return Ok(false);
+    }
+    tx.execute(format!(r#"DROP TABLE IF EXISTS "{}""#, name).as_str(), &[])?;
+    tx.execute(r#"DELETE FROM "__partitions" WHERE topic = $1"#, &[&name])?;
+    tx.execute(r#"DELETE FROM "__consumer_offsets" WHERE topic = $1"#, &[&name])?;
+    tx.commit()?;
+    Ok(true)
+}
+
 pub fn cleanup(db: &PgState) {
     debug!("Cleanup thread is awake");
+    db.coordinator.expire_members();
     let conn = db.pool.get().expect("Could not get a DB connection");
-    for (_, topic) in &db.topics {
+    conn.execute(r#"DELETE FROM "__consumer_offsets" WHERE expire_ts < now()"#, &[]).expect("Failed to delete from the DB");
+    let topics: Vec<Topic> = db.topics.read().unwrap().values().cloned().collect();
+    for topic in topics {
         if let Some(retention) = topic.retention {
             debug!("Cleaning up topic {}", topic.name);
             conn.execute(format!("DELETE FROM \"{}\" WHERE ts < now() - $1::text::interval",
@@ -261,3 +1525,17 @@ pub fn cleanup(db: &PgState) {
     }
     debug!("Cleanup thread is sleeping");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_acks_all_writes_time_out() {
+        let now = Instant::now();
+        assert_eq!(write_timeout(1, now, now), Ok(None)); // timeout=0 with acks=1 still writes
+        assert_eq!(write_timeout(0, now, now), Ok(None));
+        assert_eq!(write_timeout(-1, now, now), Err(REQUEST_TIMED_OUT));
+        assert_eq!(write_timeout(-1, now + Duration::from_millis(1500), now), Ok(Some(Duration::from_millis(1500))));
+    }
+}
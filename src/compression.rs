@@ -0,0 +1,505 @@
+// Compression codecs of the Kafka message sets.
+// The codec is stored in the lowest 3 bits of the message attributes.
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use snap;
+use lz4_flex;
+
+// The Java client wraps the snappy blocks into the xerial stream format
+const XERIAL_MAGIC: &'static [u8] = &[0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+const XERIAL_VERSION: &'static [u8] = &[0, 0, 0, 1, 0, 0, 0, 1]; // version and the minimal compatible version
+const XERIAL_BLOCK_SIZE: usize = 32 * 1024;
+
+// Kafka uses the LZ4 frame format with independent 64Kb blocks and no checksums but the header one
+const LZ4_MAGIC: &'static [u8] = &[0x04, 0x22, 0x4d, 0x18];
+const LZ4_FLG: u8 = 0x60; // version 01, independent blocks
+const LZ4_BD: u8 = 0x40;  // 64Kb blocks
+const LZ4_BLOCK_SIZE: usize = 64 * 1024;
+const LZ4_UNCOMPRESSED_BLOCK: u32 = 0x80000000;
+
+// Kafka's default message.max.bytes
+pub const DEFAULT_MAX_SIZE: usize = 1048588;
+
+// A compressed message set may not unpack to more than message.max.bytes, it is set once at startup
+static MAX_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_SIZE);
+
+pub fn set_max_size(size: usize) {
+    MAX_SIZE.store(size, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy,
+    Lz4
+}
+
+impl Compression {
+    pub fn from_attributes(attributes: u8) -> Option<Compression> {
+        match attributes & 0x07 {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Gzip),
+            2 => Some(Compression::Snappy),
+            3 => Some(Compression::Lz4),
+            _ => None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" | "uncompressed" => Some(Compression::None),
+            "gzip"                  => Some(Compression::Gzip),
+            "snappy"                => Some(Compression::Snappy),
+            "lz4"                   => Some(Compression::Lz4),
+            _                       => None
+        }
+    }
+
+    pub fn attributes(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Snappy => 2,
+            Compression::Lz4 => 3
+        }
+    }
+}
+
+pub fn decompress(codec: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    decompress_at_most(codec, data, MAX_SIZE.load(Ordering::Relaxed))
+}
+
+fn decompress_at_most(codec: Compression, data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match codec {
+        Compression::None => out.extend_from_slice(data),
+        Compression::Gzip => {
+            GzDecoder::new(data)?.take(max_size as u64 + 1).read_to_end(&mut out)?;
+            if out.len() > max_size {
+                return Err(too_large(max_size));
+            }
+        },
+        Compression::Snappy => snappy_decompress(data, max_size, &mut out)?,
+        Compression::Lz4 => lz4_decompress(data, max_size, &mut out)?
+    }
+    Ok(out)
+}
+
+pub fn compress(codec: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::Default);
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        Compression::Snappy => snappy_compress(data),
+        Compression::Lz4 => Ok(lz4_compress(data))
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> io::Result<u32> {
+    if data.len() < pos + 4 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated snappy stream"));
+    }
+    Ok(data[pos..pos + 4].iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+}
+
+// Accepts both the xerial framing and a bare snappy block (some non-Java clients send those)
+fn snappy_decompress(data: &[u8], max_size: usize, out: &mut Vec<u8>) -> io::Result<()> {
+    let mut decoder = snap::raw::Decoder::new();
+    if !data.starts_with(XERIAL_MAGIC) {
+        if snap::raw::decompress_len(data)? > max_size {
+            return Err(too_large(max_size));
+        }
+        out.extend(decoder.decompress_vec(data)?);
+        return Ok(());
+    }
+    let mut pos = XERIAL_MAGIC.len() + XERIAL_VERSION.len();
+    while pos < data.len() {
+        let size = read_u32(data, pos)? as usize;
+        pos += 4;
+        if data.len() < pos + size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated snappy block"));
+        }
+        if out.len() + snap::raw::decompress_len(&data[pos..pos + size])? > max_size {
+            return Err(too_large(max_size));
+        }
+        out.extend(decoder.decompress_vec(&data[pos..pos + size])?);
+        pos += size;
+    }
+    Ok(())
+}
+
+fn snappy_compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = snap::raw::Encoder::new();
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    out.extend_from_slice(XERIAL_MAGIC);
+    out.extend_from_slice(XERIAL_VERSION);
+    for block in data.chunks(XERIAL_BLOCK_SIZE) {
+        let compressed = encoder.compress_vec(block)?;
+        let size = compressed.len() as u32;
+        out.extend_from_slice(&[(size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8]);
+        out.extend(compressed);
+    }
+    Ok(out)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn too_large(max_size: usize) -> io::Error {
+    invalid_data(&format!("Decompressed message set is over {} bytes", max_size))
+}
+
+fn read_u32_le(data: &[u8], pos: usize) -> io::Result<u32> {
+    if data.len() < pos + 4 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated LZ4 frame"));
+    }
+    Ok(data[pos..pos + 4].iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+}
+
+fn lz4_header_checksum(descriptor: &[u8]) -> u8 {
+    (xxh32(descriptor, 0) >> 8) as u8
+}
+
+// Before KIP-57 (magic v0) Kafka computed the header checksum over the magic number as well.
+// librdkafka keeps doing that for the old message format, so both variants are accepted.
+fn lz4_decompress(data: &[u8], max_size: usize, out: &mut Vec<u8>) -> io::Result<()> {
+    if !data.starts_with(LZ4_MAGIC) || data.len() < 7 {
+        return Err(invalid_data("Not an LZ4 frame"));
+    }
+    let flg = data[4];
+    let bd = data[5];
+    if flg >> 6 != 1 {
+        return Err(invalid_data("Unsupported LZ4 frame version"));
+    }
+    let block_checksum = flg & 0x10 != 0;
+    let content_checksum = flg & 0x04 != 0;
+    let mut pos = 6;
+    if flg & 0x08 != 0 {
+        pos += 8; // content size
+    }
+    if flg & 0x01 != 0 {
+        pos += 4; // dictionary id
+    }
+    if data.len() <= pos {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated LZ4 frame"));
+    }
+    let checksum = data[pos];
+    if checksum != lz4_header_checksum(&data[4..pos]) && checksum != lz4_header_checksum(&data[0..pos]) {
+        return Err(invalid_data("LZ4 frame header checksum mismatch"));
+    }
+    pos += 1;
+    let max_block_size = match (bd >> 4) & 0x07 {
+        4 => 64 * 1024,
+        5 => 256 * 1024,
+        6 => 1024 * 1024,
+        7 => 4 * 1024 * 1024,
+        _ => return Err(invalid_data("Invalid LZ4 block size"))
+    };
+    let mut block = vec![0u8; max_block_size];
+    let start = out.len();
+    loop {
+        let header = read_u32_le(data, pos)?;
+        pos += 4;
+        if header == 0 {
+            break; // end mark
+        }
+        let size = (header & !LZ4_UNCOMPRESSED_BLOCK) as usize;
+        if data.len() < pos + size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated LZ4 block"));
+        }
+        if block_checksum && read_u32_le(data, pos + size)? != xxh32(&data[pos..pos + size], 0) {
+            return Err(invalid_data("LZ4 block checksum mismatch"));
+        }
+        let unpacked = if header & LZ4_UNCOMPRESSED_BLOCK != 0 {
+            &data[pos..pos + size]
+        } else {
+            let len = lz4_flex::block::decompress_into(&data[pos..pos + size], &mut block)
+                .map_err(|e| invalid_data(&format!("Corrupted LZ4 block: {}", e)))?;
+            &block[..len]
+        };
+        if out.len() + unpacked.len() > max_size {
+            return Err(too_large(max_size));
+        }
+        out.extend_from_slice(unpacked);
+        pos += size;
+        if block_checksum {
+            pos += 4;
+        }
+    }
+    if content_checksum && read_u32_le(data, pos)? != xxh32(&out[start..], 0) {
+        return Err(invalid_data("LZ4 content checksum mismatch"));
+    }
+    Ok(())
+}
+
+// Always writes the correct header checksum, as expected for magic v1
+fn lz4_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    out.extend_from_slice(LZ4_MAGIC);
+    out.push(LZ4_FLG);
+    out.push(LZ4_BD);
+    out.push(lz4_header_checksum(&[LZ4_FLG, LZ4_BD]));
+    for chunk in data.chunks(LZ4_BLOCK_SIZE) {
+        let compressed = lz4_flex::block::compress(chunk);
+        let (header, block) = if compressed.len() < chunk.len() {
+            (compressed.len() as u32, &compressed[..])
+        } else {
+            (chunk.len() as u32 | LZ4_UNCOMPRESSED_BLOCK, chunk)
+        };
+        out.extend_from_slice(&[header as u8, (header >> 8) as u8, (header >> 16) as u8, (header >> 24) as u8]);
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&[0, 0, 0, 0]); // end mark
+    out
+}
+
+// xxHash32, for the LZ4 frame header, block and content checksums
+fn xxh32(data: &[u8], seed: u32) -> u32 {
+    const P1: u32 = 2654435761;
+    const P2: u32 = 2246822519;
+    const P3: u32 = 3266489917;
+    const P4: u32 = 668265263;
+    const P5: u32 = 374761393;
+    fn lane(data: &[u8]) -> u32 {
+        data[..4].iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+    }
+    fn round(acc: u32, input: u32) -> u32 {
+        acc.wrapping_add(input.wrapping_mul(P2)).rotate_left(13).wrapping_mul(P1)
+    }
+    let mut rest = data;
+    let mut h = if data.len() >= 16 {
+        let mut v = [seed.wrapping_add(P1).wrapping_add(P2), seed.wrapping_add(P2), seed, seed.wrapping_sub(P1)];
+        while rest.len() >= 16 {
+            for i in 0..4 {
+                v[i] = round(v[i], lane(&rest[i * 4..]));
+            }
+            rest = &rest[16..];
+        }
+        v[0].rotate_left(1).wrapping_add(v[1].rotate_left(7)).wrapping_add(v[2].rotate_left(12)).wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(P5)
+    };
+    h = h.wrapping_add(data.len() as u32);
+    while rest.len() >= 4 {
+        h = h.wrapping_add(lane(rest).wrapping_mul(P3)).rotate_left(17).wrapping_mul(P4);
+        rest = &rest[4..];
+    }
+    for b in rest {
+        h = h.wrapping_add((*b as u32).wrapping_mul(P5)).rotate_left(11).wrapping_mul(P1);
+    }
+    h ^= h >> 15;
+    h = h.wrapping_mul(P2);
+    h ^= h >> 13;
+    h = h.wrapping_mul(P3);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::IResult;
+    use parser::{kafka_request, ApiRequest, KafkaMessage};
+
+    // Produce v2 requests (without the size header) captured from librdkafka 2.12.1 with compression.codec set.
+    // Each one is a single wrapper message holding the key "k1" with the value "v1" and a message with no key
+    // and the value "hello <codec> " repeated 16 times. librdkafka sends bare snappy blocks, not the xerial framing.
+    const SNAPPY_PRODUCE: &'static [u8] = &[
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x0a, 0x70, 0x72,
+        0x6f, 0x64, 0x75, 0x63, 0x65, 0x72, 0x2d, 0x31, 0x00, 0x01, 0x00, 0x00,
+        0x75, 0x30, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x74, 0x65, 0x73, 0x74,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x73,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x67,
+        0x10, 0x09, 0xd0, 0x1c, 0x01, 0x02, 0x00, 0x00, 0x01, 0xa1, 0x48, 0x53,
+        0x9a, 0xf1, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x51, 0x98, 0x02,
+        0x00, 0x00, 0x19, 0x01, 0x50, 0x1a, 0xf7, 0xf1, 0x0f, 0xc4, 0x01, 0x00,
+        0x00, 0x00, 0x01, 0xa1, 0x48, 0x53, 0x9a, 0xf1, 0x00, 0x00, 0x00, 0x02,
+        0x6b, 0x31, 0x01, 0x06, 0x00, 0x76, 0x01, 0x06, 0x01, 0x01, 0x01, 0x1d,
+        0x10, 0xe6, 0x49, 0x05, 0xe5, 0x60, 0x01, 0x09, 0x09, 0x26, 0x50, 0xff,
+        0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0xd0, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+        0x20, 0x73, 0x6e, 0x61, 0x70, 0x70, 0x79, 0x20, 0xfe, 0x0d, 0x00, 0xfe,
+        0x0d, 0x00, 0xee, 0x0d, 0x00, 0x0d, 0x0d,
+    ];
+
+    const GZIP_PRODUCE: &'static [u8] = &[
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x0a, 0x70, 0x72,
+        0x6f, 0x64, 0x75, 0x63, 0x65, 0x72, 0x2d, 0x31, 0x00, 0x01, 0x00, 0x00,
+        0x75, 0x30, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x74, 0x65, 0x73, 0x74,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6d,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x61,
+        0xc8, 0xeb, 0x87, 0xb4, 0x01, 0x01, 0x00, 0x00, 0x01, 0xa1, 0x48, 0x53,
+        0x9c, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x4b, 0x1f, 0x8b,
+        0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x63, 0x60, 0x80, 0x03,
+        0xa9, 0xaf, 0x57, 0x3d, 0x0e, 0x31, 0x02, 0x19, 0x8c, 0x0b, 0x3d, 0x82,
+        0xe7, 0xfc, 0x07, 0x32, 0x98, 0xb2, 0x0d, 0x41, 0x64, 0x99, 0x21, 0x54,
+        0x05, 0x48, 0xf2, 0x58, 0xf2, 0x41, 0xc7, 0x22, 0x84, 0x2a, 0x10, 0x00,
+        0x72, 0x36, 0x64, 0xa4, 0xe6, 0xe4, 0xe4, 0x2b, 0xa4, 0x57, 0x65, 0x16,
+        0x28, 0x0c, 0x09, 0x26, 0x00, 0xd2, 0xae, 0xa3, 0x41, 0xf8, 0x00, 0x00,
+        0x00,
+    ];
+
+    const LZ4_PRODUCE: &'static [u8] = &[
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x0a, 0x70, 0x72,
+        0x6f, 0x64, 0x75, 0x63, 0x65, 0x72, 0x2d, 0x31, 0x00, 0x01, 0x00, 0x00,
+        0x75, 0x30, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x74, 0x65, 0x73, 0x74,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x74,
+        0x31, 0x27, 0x19, 0x57, 0x01, 0x03, 0x00, 0x00, 0x01, 0xa1, 0x48, 0x53,
+        0x9f, 0x13, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x5e, 0x04, 0x22,
+        0x4d, 0x18, 0x60, 0x40, 0x82, 0x4f, 0x00, 0x00, 0x00, 0x16, 0x00, 0x01,
+        0x00, 0xf0, 0x06, 0x1a, 0xbd, 0x97, 0x2b, 0xab, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0xa1, 0x48, 0x53, 0x9f, 0x13, 0x00, 0x00, 0x00, 0x02, 0x6b, 0x31,
+        0x06, 0x00, 0x10, 0x76, 0x06, 0x00, 0x00, 0x02, 0x00, 0x00, 0x1d, 0x00,
+        0x50, 0xb6, 0x03, 0x74, 0xde, 0xf2, 0x09, 0x00, 0x02, 0x26, 0x00, 0xff,
+        0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0xa0, 0x68, 0x65, 0x6c,
+        0x6c, 0x6f, 0x20, 0x6c, 0x7a, 0x34, 0x20, 0x0a, 0x00, 0x7e, 0x50, 0x20,
+        0x6c, 0x7a, 0x34, 0x20, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // librdkafka does not set the LZ4 content checksum flag (sarama does), this one comes from the same client
+    // built with contentChecksumFlag enabled. The last 4 bytes are the content checksum.
+    const LZ4_CONTENT_CHECKSUM_PRODUCE: &'static [u8] = &[
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x0a, 0x70, 0x72,
+        0x6f, 0x64, 0x75, 0x63, 0x65, 0x72, 0x2d, 0x31, 0x00, 0x01, 0x00, 0x00,
+        0x75, 0x30, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x74, 0x65, 0x73, 0x74,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x84,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x78,
+        0xc7, 0x7f, 0xee, 0xe3, 0x01, 0x03, 0x00, 0x00, 0x01, 0xa1, 0x48, 0x55,
+        0x7a, 0x1f, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x62, 0x04, 0x22,
+        0x4d, 0x18, 0x64, 0x40, 0xa7, 0x4f, 0x00, 0x00, 0x00, 0x16, 0x00, 0x01,
+        0x00, 0xf0, 0x06, 0x1a, 0x8e, 0xda, 0x48, 0x04, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0xa1, 0x48, 0x55, 0x7a, 0x1f, 0x00, 0x00, 0x00, 0x02, 0x6b, 0x31,
+        0x06, 0x00, 0x10, 0x76, 0x06, 0x00, 0x00, 0x02, 0x00, 0x00, 0x1d, 0x00,
+        0x50, 0xb6, 0x33, 0x0f, 0x1e, 0x22, 0x09, 0x00, 0x02, 0x26, 0x00, 0xff,
+        0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0xa0, 0x68, 0x65, 0x6c,
+        0x6c, 0x6f, 0x20, 0x6c, 0x7a, 0x34, 0x20, 0x0a, 0x00, 0x7e, 0x50, 0x20,
+        0x6c, 0x7a, 0x34, 0x20, 0x00, 0x00, 0x00, 0x00, 0xe6, 0x01, 0xe5, 0xaa,
+    ];
+
+    fn produced_messages(request: &[u8]) -> Option<Vec<KafkaMessage>> {
+        match kafka_request(request) {
+            IResult::Done(_, req) => match req.req {
+                ApiRequest::Publish { topics, .. } => topics.into_iter().next().unwrap().messages.into_iter().next().unwrap().1,
+                ref other => panic!("Not a produce request {:?}", other)
+            },
+            other => panic!("Failed to parse {:?}", other)
+        }
+    }
+
+    fn assert_unwrapped(request: &[u8], codec: &str) {
+        let messages = produced_messages(request).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].key, Some(b"k1".to_vec()));
+        assert_eq!(messages[0].value, Some(b"v1".to_vec()));
+        assert_eq!(messages[1].key, None);
+        assert_eq!(messages[1].value, Some(format!("hello {} ", codec).repeat(16).into_bytes()));
+    }
+
+    #[test]
+    fn snappy_produce_is_unwrapped() {
+        assert_unwrapped(SNAPPY_PRODUCE, "snappy");
+    }
+
+    #[test]
+    fn snappy_round_trip_over_several_blocks() {
+        let data: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+        let compressed = compress(Compression::Snappy, &data).unwrap();
+        assert!(compressed.starts_with(XERIAL_MAGIC));
+        assert_eq!(decompress(Compression::Snappy, &compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn snappy_bare_block() {
+        let block = snap::raw::Encoder::new().compress_vec(b"no framing here").unwrap();
+        assert_eq!(decompress(Compression::Snappy, &block).unwrap(), b"no framing here".to_vec());
+    }
+
+    #[test]
+    fn snappy_truncated_stream() {
+        let compressed = compress(Compression::Snappy, b"some bytes to compress").unwrap();
+        assert!(decompress(Compression::Snappy, &compressed[..compressed.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn gzip_produce_is_unwrapped() {
+        assert_unwrapped(GZIP_PRODUCE, "gzip");
+    }
+
+    #[test]
+    fn corrupt_gzip_wrapper_rejects_the_partition() {
+        let mut request = GZIP_PRODUCE.to_vec();
+        let header = request.windows(2).position(|w| w == &[0x1f, 0x8b]).unwrap();
+        request[header] = 0;
+        assert!(produced_messages(&request).is_none());
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let data: Vec<u8> = (0..100000).map(|i| (i % 249) as u8).collect();
+        let compressed = compress(Compression::Gzip, &data).unwrap();
+        assert!(compressed.starts_with(&[0x1f, 0x8b]));
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(Compression::Gzip, &compressed).unwrap(), data);
+        assert!(decompress(Compression::Gzip, &compressed[..compressed.len() - 5]).is_err());
+    }
+
+    #[test]
+    fn xxh32_reference_values() {
+        assert_eq!(xxh32(b"", 0), 0x02cc5d05);
+        assert_eq!(xxh32(b"abc", 0), 0x32d153ff);
+        assert_eq!(xxh32(b"Nobody inspects the spammish repetition", 0), 0xe2293b2f);
+    }
+
+    #[test]
+    fn lz4_round_trip_over_several_blocks() {
+        let data: Vec<u8> = (0..200000).map(|i| (i % 253) as u8).collect();
+        let compressed = compress(Compression::Lz4, &data).unwrap();
+        assert_eq!(compressed[6], lz4_header_checksum(&[LZ4_FLG, LZ4_BD]));
+        assert_eq!(decompress(Compression::Lz4, &compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn lz4_broken_header_checksum_of_magic_v0() {
+        let mut compressed = compress(Compression::Lz4, b"old librdkafka").unwrap();
+        compressed[6] = lz4_header_checksum(&compressed[0..6]);
+        assert_eq!(decompress(Compression::Lz4, &compressed).unwrap(), b"old librdkafka".to_vec());
+        compressed[6] = compressed[6].wrapping_add(1);
+        assert!(decompress(Compression::Lz4, &compressed).is_err());
+    }
+
+    #[test]
+    fn lz4_produce_is_unwrapped() {
+        assert_unwrapped(LZ4_PRODUCE, "lz4");
+    }
+
+    #[test]
+    fn lz4_content_checksum_is_verified() {
+        assert_unwrapped(LZ4_CONTENT_CHECKSUM_PRODUCE, "lz4");
+        let mut request = LZ4_CONTENT_CHECKSUM_PRODUCE.to_vec();
+        let last = request.len() - 1;
+        request[last] ^= 1;
+        assert!(produced_messages(&request).is_none());
+    }
+
+    #[test]
+    fn decompressed_size_is_capped() {
+        let data: Vec<u8> = (0..100000).map(|i| (i % 247) as u8).collect();
+        for &codec in &[Compression::Gzip, Compression::Snappy, Compression::Lz4] {
+            let compressed = compress(codec, &data).unwrap();
+            assert_eq!(decompress_at_most(codec, &compressed, data.len()).unwrap(), data);
+            assert!(decompress_at_most(codec, &compressed, data.len() - 1).is_err());
+        }
+        let block = snap::raw::Encoder::new().compress_vec(&data).unwrap();
+        assert!(decompress_at_most(Compression::Snappy, &block, 1000).is_err());
+    }
+}
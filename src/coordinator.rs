@@ -0,0 +1,612 @@
+// Consumer group coordinator.
+// Keeps the members of every group in memory, elects the leader, waits for all
+// the members to join and hands out the assignments computed by the leader.
+// Nothing here is persisted: after a restart the consumers simply rejoin.
+// The members that have to wait for the rest of the group get a receiver that completes
+// whenever the group changes, and ask again then or by the instant they are given.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::sync::oneshot;
+use writer::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupState {
+    Empty,
+    PreparingRebalance,
+    AwaitingSync,
+    Stable
+}
+
+impl GroupState {
+    // As DescribeGroups reports it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            GroupState::Empty => "Empty",
+            GroupState::PreparingRebalance => "PreparingRebalance",
+            GroupState::AwaitingSync => "CompletingRebalance",
+            GroupState::Stable => "Stable"
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub id: String,
+    pub client_id: String,
+    pub client_host: String,
+    pub protocols: Vec<(String, Option<Vec<u8>>)>,
+    pub assignment: Option<Vec<u8>>,
+    session_timeout: Duration,
+    rebalance_timeout: Duration,
+    last_heartbeat: Instant,
+    joined: bool,
+    join_result: Option<JoinResult>,
+    sync_deadline: Option<Instant> // how long a follower waits for the leader's assignments
+}
+
+#[derive(Debug, Clone)]
+pub struct JoinResult {
+    pub error_code: u16,
+    pub generation_id: i32,
+    pub protocol: Option<String>,
+    pub leader_id: String,
+    pub member_id: String,
+    pub members: Vec<(String, Option<Vec<u8>>)>
+}
+impl JoinResult {
+    fn error(error_code: u16, member_id: &str) -> JoinResult {
+        JoinResult {
+            error_code: error_code,
+            generation_id: -1,
+            protocol: None,
+            leader_id: String::new(),
+            member_id: member_id.to_string(),
+            members: Vec::new()
+        }
+    }
+}
+
+pub enum Joining {
+    Joined(JoinResult),
+    Waiting(String, oneshot::Receiver<()>, Instant) // the member id
+}
+
+pub enum Syncing {
+    Synced(u16, Option<Vec<u8>>),
+    Waiting(oneshot::Receiver<()>, Instant)
+}
+
+#[derive(Debug)]
+pub struct Group {
+    pub state: GroupState,
+    pub generation_id: i32,
+    pub protocol_type: Option<String>,
+    pub protocol: Option<String>,
+    pub leader_id: Option<String>,
+    pub members: Vec<Member>, // in the join order
+    rebalance_deadline: Instant,
+    waiting: Vec<oneshot::Sender<()>>
+}
+
+impl Group {
+    fn new() -> Group {
+        Group {
+            state: GroupState::Empty,
+            generation_id: 0,
+            protocol_type: None,
+            protocol: None,
+            leader_id: None,
+            members: Vec::new(),
+            rebalance_deadline: Instant::now(),
+            waiting: Vec::new()
+        }
+    }
+
+    fn park(&mut self) -> oneshot::Receiver<()> {
+        let (waiter, woken) = oneshot::channel();
+        self.waiting.retain(|w| !w.is_canceled());
+        self.waiting.push(waiter);
+        woken
+    }
+
+    fn wake(&mut self) {
+        for waiter in mem::take(&mut self.waiting) {
+            let _ = waiter.send(()); // the request may be gone already
+        }
+    }
+
+    fn member(&self, id: &str) -> Option<&Member> {
+        self.members.iter().find(|m| m.id == id)
+    }
+
+    fn member_mut(&mut self, id: &str) -> Option<&mut Member> {
+        self.members.iter_mut().find(|m| m.id == id)
+    }
+
+    fn is_leader(&self, id: &str) -> bool {
+        self.leader_id.as_ref().map(|l| l == id).unwrap_or(false)
+    }
+
+    // A new member must speak the same protocol type and share at least one protocol with everybody else
+    fn accepts(&self, member_id: &str, protocol_type: &str, protocols: &Vec<(String, Option<Vec<u8>>)>) -> bool {
+        if self.members.iter().all(|m| m.id == member_id) {
+            return !protocols.is_empty();
+        }
+        if self.protocol_type.as_ref().map(|t| t != protocol_type).unwrap_or(false) {
+            return false;
+        }
+        protocols.iter().any(|&(ref name, _)| {
+            self.members.iter()
+                .filter(|m| m.id != member_id)
+                .all(|m| m.protocols.iter().any(|p| &p.0 == name))
+        })
+    }
+
+    // The first protocol in the leader's preference list that every member supports
+    fn select_protocol(&self) -> Option<String> {
+        let leader = self.leader_id.as_ref().and_then(|l| self.member(l))?;
+        leader.protocols.iter()
+            .map(|p| &p.0)
+            .find(|name| self.members.iter().all(|m| m.protocols.iter().any(|p| &p.0 == *name)))
+            .cloned()
+    }
+
+    fn expire_members(&mut self, now: Instant) {
+        if self.state == GroupState::PreparingRebalance || self.state == GroupState::Empty {
+            // The join barrier has its own deadline
+            return;
+        }
+        let before = self.members.len();
+        self.members.retain(|m| now.duration_since(m.last_heartbeat) <= m.session_timeout);
+        if self.members.len() != before {
+            info!("{} member(s) of a group have timed out", before - self.members.len());
+            self.rebalance(now);
+        }
+    }
+
+    fn rebalance(&mut self, now: Instant) {
+        self.wake();
+        if self.members.is_empty() {
+            self.state = GroupState::Empty;
+            self.leader_id = None;
+            self.protocol = None;
+            self.protocol_type = None;
+        } else if self.state != GroupState::PreparingRebalance {
+            self.state = GroupState::PreparingRebalance;
+            for m in &mut self.members {
+                m.joined = false;
+            }
+            let timeout = self.members.iter().map(|m| m.rebalance_timeout).max().unwrap_or_default();
+            self.rebalance_deadline = now + timeout;
+        }
+    }
+
+    fn all_joined(&self) -> bool {
+        self.members.iter().all(|m| m.joined)
+    }
+
+    // Close the join barrier: drop whoever did not rejoin and start the next generation
+    fn complete_join(&mut self) {
+        self.members.retain(|m| m.joined);
+        self.generation_id += 1;
+        if self.members.is_empty() {
+            self.rebalance(Instant::now());
+            return;
+        }
+        let leader_present = self.leader_id.as_ref().map(|l| self.member(l).is_some()).unwrap_or(false);
+        if !leader_present {
+            self.leader_id = Some(self.members[0].id.to_string());
+        }
+        self.protocol = self.select_protocol();
+        self.state = GroupState::AwaitingSync;
+        let leader_id = self.leader_id.clone().unwrap_or_default();
+        let metadata: Vec<(String, Option<Vec<u8>>)> = match self.protocol {
+            Some(ref name) => self.members.iter()
+                .map(|m| (m.id.to_string(), m.protocols.iter().find(|p| &p.0 == name).and_then(|p| p.1.clone())))
+                .collect(),
+            None => Vec::new()
+        };
+        let generation_id = self.generation_id;
+        let protocol = self.protocol.clone();
+        for m in &mut self.members {
+            m.assignment = None;
+            m.sync_deadline = None;
+            m.join_result = Some(JoinResult {
+                error_code: NONE,
+                generation_id: generation_id,
+                protocol: protocol.clone(),
+                leader_id: leader_id.to_string(),
+                member_id: m.id.to_string(),
+                members: if m.id == leader_id { metadata.clone() } else { Vec::new() } // only the leader gets the list
+            });
+        }
+        self.wake();
+    }
+}
+
+#[derive(Debug)]
+pub struct Coordinator {
+    groups: Mutex<HashMap<String, Group>>,
+    member_seq: AtomicUsize,
+    started: u64
+}
+
+impl Coordinator {
+    pub fn new() -> Coordinator {
+        Coordinator {
+            groups: Mutex::new(HashMap::new()),
+            member_seq: AtomicUsize::new(0),
+            started: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+        }
+    }
+
+    fn new_member_id(&self, client_id: &str) -> String {
+        format!("{}-{}-{}", client_id, self.started, self.member_seq.fetch_add(1, Ordering::SeqCst))
+    }
+
+    // Waits until every known member of the group has (re)joined or the rebalance timeout has passed
+    pub fn join(&self, group_id: &str, member_id: &str, client_id: &str, client_host: &str, session_timeout: u32, rebalance_timeout: u32,
+                protocol_type: &str, protocols: &Vec<(String, Option<Vec<u8>>)>) -> Joining {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        let id = {
+            let group = groups.entry(group_id.to_string()).or_insert_with(Group::new);
+            group.expire_members(now);
+            if !member_id.is_empty() && group.member(member_id).is_none() {
+                return Joining::Joined(JoinResult::error(UNKNOWN_MEMBER_ID, member_id));
+            }
+            if !group.accepts(member_id, protocol_type, protocols) {
+                return Joining::Joined(JoinResult::error(INCONSISTENT_GROUP_PROTOCOL, member_id));
+            }
+            let id = if member_id.is_empty() { self.new_member_id(client_id) } else { member_id.to_string() };
+            if group.member(&id).is_none() {
+                group.members.push(Member {
+                    id: id.to_string(),
+                    client_id: client_id.to_string(),
+                    client_host: client_host.to_string(),
+                    protocols: Vec::new(),
+                    assignment: None,
+                    session_timeout: Duration::from_millis(0),
+                    rebalance_timeout: Duration::from_millis(0),
+                    last_heartbeat: now,
+                    joined: false,
+                    join_result: None,
+                    sync_deadline: None
+                });
+            }
+            group.protocol_type = Some(protocol_type.to_string());
+            {
+                let member = group.member_mut(&id).unwrap();
+                member.protocols = protocols.to_vec();
+                member.client_host = client_host.to_string();
+                member.session_timeout = Duration::from_millis(session_timeout as u64);
+                member.rebalance_timeout = Duration::from_millis(rebalance_timeout as u64);
+                member.last_heartbeat = now;
+            }
+            group.rebalance(now);
+            let member = group.member_mut(&id).unwrap();
+            member.joined = true;
+            member.join_result = None;
+            id
+        };
+        debug!("Member {} joined group {}", id, group_id);
+        Coordinator::joining(&mut groups, group_id, &id)
+    }
+
+    // A member that got Joining::Waiting asks again here
+    pub fn poll_join(&self, group_id: &str, member_id: &str) -> Joining {
+        let mut groups = self.groups.lock().unwrap();
+        Coordinator::joining(&mut groups, group_id, member_id)
+    }
+
+    fn joining(groups: &mut HashMap<String, Group>, group_id: &str, id: &str) -> Joining {
+        let group = match groups.get_mut(group_id) {
+            Some(g) => g,
+            None => return Joining::Joined(JoinResult::error(UNKNOWN_MEMBER_ID, id))
+        };
+        loop {
+            match group.member_mut(id) {
+                None => return Joining::Joined(JoinResult::error(UNKNOWN_MEMBER_ID, id)),
+                Some(m) => if let Some(result) = m.join_result.take() {
+                    return Joining::Joined(result);
+                }
+            }
+            if group.state != GroupState::PreparingRebalance {
+                return Joining::Joined(JoinResult::error(REBALANCE_IN_PROGRESS, id));
+            }
+            if !group.all_joined() && Instant::now() < group.rebalance_deadline {
+                return Joining::Waiting(id.to_string(), group.park(), group.rebalance_deadline);
+            }
+            group.complete_join();
+            info!("Group {} is at generation {} with {} member(s)", group_id, group.generation_id, group.members.len());
+        }
+    }
+
+    // The leader brings the assignments, everybody else waits for them
+    pub fn sync(&self, group_id: &str, generation_id: i32, member_id: &str, assignments: &Vec<(String, Option<Vec<u8>>)>) -> Syncing {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        let group = match groups.get_mut(group_id) {
+            Some(g) => g,
+            None => return Syncing::Synced(UNKNOWN_MEMBER_ID, None)
+        };
+        group.expire_members(now);
+        match group.member_mut(member_id) {
+            None => return Syncing::Synced(UNKNOWN_MEMBER_ID, None),
+            Some(m) => m.last_heartbeat = now
+        }
+        if generation_id != group.generation_id {
+            return Syncing::Synced(ILLEGAL_GENERATION, None);
+        }
+        if group.state == GroupState::AwaitingSync && group.is_leader(member_id) {
+            for &(ref id, ref assignment) in assignments {
+                if let Some(m) = group.member_mut(id) {
+                    m.assignment = assignment.clone();
+                }
+            }
+            group.state = GroupState::Stable;
+            group.wake();
+        }
+        match group.state {
+            GroupState::Stable => Syncing::Synced(NONE, group.member(member_id).and_then(|m| m.assignment.clone())),
+            GroupState::AwaitingSync => {
+                let deadline = {
+                    let member = group.member_mut(member_id).unwrap();
+                    *member.sync_deadline.get_or_insert(now + member.session_timeout)
+                };
+                if now >= deadline {
+                    Syncing::Synced(REBALANCE_IN_PROGRESS, None)
+                } else {
+                    Syncing::Waiting(group.park(), deadline)
+                }
+            },
+            _ => Syncing::Synced(REBALANCE_IN_PROGRESS, None)
+        }
+    }
+
+    pub fn heartbeat(&self, group_id: &str, generation_id: i32, member_id: &str) -> u16 {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        let group = match groups.get_mut(group_id) {
+            Some(g) => g,
+            None => return UNKNOWN_MEMBER_ID
+        };
+        group.expire_members(now);
+        if group.member(member_id).is_none() {
+            return UNKNOWN_MEMBER_ID;
+        }
+        if group.state == GroupState::AwaitingSync {
+            return REBALANCE_IN_PROGRESS;
+        }
+        if generation_id != group.generation_id {
+            return ILLEGAL_GENERATION;
+        }
+        group.member_mut(member_id).unwrap().last_heartbeat = now;
+        if group.state == GroupState::PreparingRebalance {
+            REBALANCE_IN_PROGRESS
+        } else {
+            NONE
+        }
+    }
+
+    pub fn leave(&self, group_id: &str, member_id: &str) -> u16 {
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get_mut(group_id) {
+            Some(g) => g,
+            None => return UNKNOWN_MEMBER_ID
+        };
+        match group.members.iter().position(|m| m.id == member_id) {
+            None => return UNKNOWN_MEMBER_ID,
+            Some(i) => group.members.remove(i)
+        };
+        info!("Member {} left group {}", member_id, group_id);
+        group.rebalance(Instant::now());
+        NONE
+    }
+
+    // Offsets may be committed by the current generation or, outside of any group, with generation -1
+    pub fn validate_commit(&self, group_id: &str, generation_id: i32, member_id: &str) -> u16 {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        let group = match groups.get_mut(group_id) {
+            Some(g) => g,
+            None => return if generation_id < 0 { NONE } else { ILLEGAL_GENERATION }
+        };
+        group.expire_members(now);
+        if generation_id < 0 && group.state == GroupState::Empty {
+            return NONE;
+        }
+        if group.state == GroupState::AwaitingSync {
+            return REBALANCE_IN_PROGRESS;
+        }
+        match group.member_mut(member_id) {
+            None => return UNKNOWN_MEMBER_ID,
+            Some(m) => m.last_heartbeat = now
+        }
+        if generation_id != group.generation_id {
+            return ILLEGAL_GENERATION;
+        }
+        NONE
+    }
+
+    // The groups with their protocol type
+    pub fn list(&self) -> Vec<(String, String)> {
+        let groups = self.groups.lock().unwrap();
+        groups.iter()
+            .map(|(id, g)| (id.to_string(), g.protocol_type.clone().unwrap_or_default()))
+            .collect()
+    }
+
+    pub fn describe(&self, group_id: &str) -> Option<GroupDescription> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.get_mut(group_id)?;
+        group.expire_members(Instant::now());
+        let members = group.members.iter().map(|m| MemberDescription {
+            member_id: m.id.to_string(),
+            client_id: m.client_id.to_string(),
+            client_host: m.client_host.to_string(),
+            // The metadata of the selected protocol is only settled in a stable group
+            metadata: match (group.state, &group.protocol) {
+                (GroupState::Stable, &Some(ref name)) => m.protocols.iter().find(|p| &p.0 == name).and_then(|p| p.1.clone()),
+                _ => None
+            },
+            assignment: m.assignment.clone()
+        }).collect();
+        Some(GroupDescription {
+            error_code: NONE,
+            group_id: group_id.to_string(),
+            state: group.state.name().to_string(),
+            protocol_type: group.protocol_type.clone().unwrap_or_default(),
+            protocol: group.protocol.clone().unwrap_or_default(),
+            members: members
+        })
+    }
+
+    // Called from the cleanup thread to notice members that are gone
+    pub fn expire_members(&self) {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        for (_, group) in groups.iter_mut() {
+            group.expire_members(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    fn protocols() -> Vec<(String, Option<Vec<u8>>)> {
+        vec![("range".to_string(), Some(vec![1]))]
+    }
+
+    fn join(coordinator: &Coordinator, member_id: &str) -> Joining {
+        coordinator.join("g", member_id, "client", "/127.0.0.1", 10000, 10000, "consumer", &protocols())
+    }
+
+    fn joined(joining: Joining) -> JoinResult {
+        match joining {
+            Joining::Joined(result) => result,
+            Joining::Waiting(id, _, _) => panic!("Member {} is still waiting to join", id)
+        }
+    }
+
+    fn waiting(joining: Joining) -> (String, oneshot::Receiver<()>) {
+        match joining {
+            Joining::Joined(result) => panic!("Member {} joined already", result.member_id),
+            Joining::Waiting(id, woken, until) => {
+                assert!(until > Instant::now());
+                (id, woken)
+            }
+        }
+    }
+
+    fn synced(syncing: Syncing) -> (u16, Option<Vec<u8>>) {
+        match syncing {
+            Syncing::Synced(error_code, assignment) => (error_code, assignment),
+            Syncing::Waiting(..) => panic!("Still waiting for the assignments")
+        }
+    }
+
+    // Two members at generation 2, the first one is the leader
+    fn stable_pair() -> (Coordinator, String, String) {
+        let coordinator = Coordinator::new();
+        let a = joined(join(&coordinator, "")).member_id;
+        synced(coordinator.sync("g", 1, &a, &vec![(a.to_string(), Some(vec![1]))]));
+        let (b, _) = waiting(join(&coordinator, ""));
+        joined(join(&coordinator, &a));
+        joined(coordinator.poll_join("g", &b));
+        synced(coordinator.sync("g", 2, &a, &vec![(a.to_string(), Some(vec![1])), (b.to_string(), Some(vec![2]))]));
+        synced(coordinator.sync("g", 2, &b, &Vec::new()));
+        (coordinator, a, b)
+    }
+
+    #[test]
+    fn single_member_joins_and_syncs() {
+        let coordinator = Coordinator::new();
+        let joined = joined(join(&coordinator, ""));
+        assert_eq!(joined.error_code, NONE);
+        assert_eq!(joined.generation_id, 1);
+        assert_eq!(joined.leader_id, joined.member_id);
+        assert_eq!(joined.protocol, Some("range".to_string()));
+        assert_eq!(joined.members, vec![(joined.member_id.to_string(), Some(vec![1]))]);
+        let synced = synced(coordinator.sync("g", 1, &joined.member_id, &vec![(joined.member_id.to_string(), Some(vec![42]))]));
+        assert_eq!(synced, (NONE, Some(vec![42])));
+        assert_eq!(coordinator.heartbeat("g", 1, &joined.member_id), NONE);
+        assert_eq!(coordinator.describe("g").unwrap().state, "Stable");
+    }
+
+    #[test]
+    fn second_member_waits_for_the_first_to_rejoin() {
+        let coordinator = Coordinator::new();
+        let a = joined(join(&coordinator, "")).member_id;
+        synced(coordinator.sync("g", 1, &a, &vec![(a.to_string(), Some(vec![1]))]));
+        let (b, woken) = waiting(join(&coordinator, ""));
+        // The first member learns about the rebalance from its heartbeat
+        assert_eq!(coordinator.heartbeat("g", 1, &a), REBALANCE_IN_PROGRESS);
+        assert_eq!(coordinator.describe("g").unwrap().state, "PreparingRebalance");
+        let rejoined = joined(join(&coordinator, &a));
+        assert!(woken.wait().is_ok());
+        let b = joined(coordinator.poll_join("g", &b));
+        assert_eq!((rejoined.generation_id, b.generation_id), (2, 2));
+        assert_eq!((rejoined.leader_id.as_str(), b.leader_id.as_str()), (a.as_str(), a.as_str()));
+        assert_eq!(rejoined.members.len(), 2);
+        assert!(b.members.is_empty()); // only the leader gets the members
+        // The follower waits for the leader's assignments
+        let woken = match coordinator.sync("g", 2, &b.member_id, &Vec::new()) {
+            Syncing::Waiting(woken, _) => woken,
+            Syncing::Synced(..) => panic!("The follower got its assignment before the leader synced")
+        };
+        assert_eq!(synced(coordinator.sync("g", 2, &a, &vec![(a.to_string(), Some(vec![1])), (b.member_id.to_string(), Some(vec![2]))])),
+                   (NONE, Some(vec![1])));
+        assert!(woken.wait().is_ok());
+        assert_eq!(synced(coordinator.sync("g", 2, &b.member_id, &Vec::new())), (NONE, Some(vec![2])));
+    }
+
+    #[test]
+    fn stale_members_are_rejected() {
+        let coordinator = Coordinator::new();
+        let a = joined(join(&coordinator, "")).member_id;
+        synced(coordinator.sync("g", 1, &a, &vec![(a.to_string(), Some(vec![1]))]));
+        assert_eq!(coordinator.heartbeat("g", 0, &a), ILLEGAL_GENERATION);
+        assert_eq!(coordinator.heartbeat("g", 1, "gone"), UNKNOWN_MEMBER_ID);
+        assert_eq!(coordinator.heartbeat("other", 1, &a), UNKNOWN_MEMBER_ID);
+        assert_eq!(coordinator.validate_commit("g", 0, &a), ILLEGAL_GENERATION);
+        assert_eq!(coordinator.validate_commit("g", 1, "gone"), UNKNOWN_MEMBER_ID);
+        assert_eq!(coordinator.validate_commit("g", 1, &a), NONE);
+        assert_eq!(coordinator.validate_commit("other", -1, ""), NONE); // a consumer outside of any group
+        assert_eq!(joined(join(&coordinator, "gone")).error_code, UNKNOWN_MEMBER_ID);
+    }
+
+    #[test]
+    fn expired_member_sends_a_stable_group_back_to_rebalance() {
+        let (coordinator, a, b) = stable_pair();
+        assert_eq!(coordinator.describe("g").unwrap().state, "Stable");
+        coordinator.groups.lock().unwrap().get_mut("g").unwrap().member_mut(&b).unwrap().last_heartbeat -= Duration::from_secs(11);
+        coordinator.expire_members();
+        let description = coordinator.describe("g").unwrap();
+        assert_eq!(description.state, "PreparingRebalance");
+        assert_eq!(description.members.len(), 1);
+        assert_eq!(coordinator.heartbeat("g", 2, &a), REBALANCE_IN_PROGRESS);
+        assert_eq!(coordinator.heartbeat("g", 2, &b), UNKNOWN_MEMBER_ID);
+        let rejoined = joined(join(&coordinator, &a));
+        assert_eq!((rejoined.generation_id, rejoined.members.len()), (3, 1));
+    }
+
+    #[test]
+    fn members_that_do_not_rejoin_are_dropped_at_the_rebalance_deadline() {
+        let (coordinator, a, b) = stable_pair();
+        let (_, woken) = waiting(join(&coordinator, &a));
+        // The parked join runs again once its deadline is over
+        coordinator.groups.lock().unwrap().get_mut("g").unwrap().rebalance_deadline = Instant::now();
+        let rejoined = joined(coordinator.poll_join("g", &a));
+        assert_eq!((rejoined.generation_id, rejoined.members.len()), (3, 1));
+        assert!(woken.wait().is_ok());
+        assert_eq!(coordinator.heartbeat("g", 3, &b), UNKNOWN_MEMBER_ID);
+    }
+}
@@ -9,6 +9,8 @@ extern crate tokio_timer;
 // DB pool
 extern crate r2d2;
 extern crate r2d2_postgres;
+extern crate postgres;
+extern crate fallible_iterator;
 
 // Parser for the requests
 #[macro_use]
@@ -25,6 +27,23 @@ extern crate log4rs;
 extern crate hostname;
 extern crate crc;
 
+// Compressed message sets
+extern crate flate2;
+extern crate snap;
+extern crate lz4_flex;
+
+// SASL/SCRAM
+extern crate sha2;
+extern crate hmac;
+#[cfg(test)]
+extern crate pbkdf2;
+extern crate base64;
+extern crate rand;
+
+// TLS listener
+extern crate openssl;
+extern crate tokio_openssl;
+
 // Needed to parse the config file
 extern crate config;
 #[macro_use]
@@ -32,25 +51,35 @@ extern crate serde_derive;
 
 use std::io;
 use std::str;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
 use bytes::BytesMut;
 use tokio_io::codec::{Encoder, Decoder};
-use tokio_io::AsyncRead;
-use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::reactor::{Core, Handle};
 use tokio_core::net::TcpListener;
-use tokio_service::{Service, NewService};
+use tokio_service::Service;
 use futures::{future, Future, Stream, Sink, BoxFuture};
+use futures::future::Loop;
 use tokio_timer::Timer;
 use futures_cpupool::CpuPool;
 use nom::IResult;
+use tokio_openssl::SslAcceptorExt;
 
 mod settings;
 mod parser;
 mod backend;
+mod coordinator;
+mod notifier;
 mod writer;
+mod compression;
+mod auth;
+mod tls;
+mod acl;
 
-use settings::Settings;
-use parser::KafkaRequest;
+use settings::{Settings, SecurityProtocol};
+use parser::{KafkaRequest, ApiRequest};
 use writer::KafkaResponse;
 
 pub struct KafkaCodec;
@@ -89,8 +118,26 @@ impl Encoder for KafkaCodec {
 #[derive(Clone)]
 pub struct KafkaService {
     thread_pool: CpuPool,
+    timer: Timer, // for the parked requests
     db_pool: backend::PgState,
-    timer: tokio_timer::Timer,
+    session: Arc<Mutex<backend::Session>>,
+}
+
+// The longest a parked request sleeps at once, well within the timer's max timeout. It runs again after that.
+const MAX_PARK: u64 = 60000;
+
+impl KafkaService {
+    // The service of a new connection, the principal comes from its client certificate
+    fn connection(&self, listener: &str, peer: SocketAddr, principal: Option<String>) -> KafkaService {
+        let mut session = backend::Session::new(peer.to_string(), listener.to_string());
+        session.principal = principal;
+        KafkaService {
+            thread_pool: self.thread_pool.clone(),
+            timer: self.timer.clone(),
+            db_pool: self.db_pool.clone(),
+            session: Arc::new(Mutex::new(session)),
+        }
+    }
 }
 
 impl Service for KafkaService {
@@ -100,39 +147,99 @@ impl Service for KafkaService {
     type Future = BoxFuture<Self::Response, Self::Error>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let db = self.db_pool.clone();
-        let timer = self.timer.clone();
-        let f = self.thread_pool.spawn_fn(move || {
-            debug!("Sending a request to the backend {:?}", req);
-            let response = backend::handle_request(req, &db);
-            debug!("Response from the backend {:?}", response);
-            let delay = if response.is_empty() {1000} else {0};
-            timer.sleep(Duration::from_millis(delay))
-                 .then(|_| future::ok(response))
+        if !backend::authenticated(&req, &self.session, &self.db_pool) {
+            // Kafka closes the connection as well, the clients report it as an authentication problem
+            warn!("Closing the connection from {}, it did not authenticate", self.session.lock().unwrap().peer);
+            return future::err(io::Error::new(io::ErrorKind::PermissionDenied, "Not authenticated")).boxed();
+        }
+        let service = self.clone();
+        let deadline = Instant::now() + Duration::from_millis(match req.req {
+            ApiRequest::Fetch { max_wait, .. } => max_wait as u64,
+            _ => 0
         });
-        f.boxed()
+        let req = Arc::new(req);
+        // Fetches park without holding a pool thread, until there is new data or their max wait is over.
+        // The group requests park the same way while the rest of the group catches up.
+        future::loop_fn(Instant::now() >= deadline, move |expired| {
+            let (db, session, req) = (service.db_pool.clone(), service.session.clone(), req.clone());
+            let timer = service.timer.clone();
+            service.thread_pool.spawn_fn(move || {
+                debug!("Sending a request to the backend {:?}", req);
+                future::ok::<_, io::Error>(backend::handle_request(&req, &session, expired, &db))
+            }).and_then(move |handled| match handled {
+                backend::Handled::Response(response) => {
+                    debug!("Response from the backend {:?}", response);
+                    future::ok(Loop::Break(response)).boxed()
+                },
+                backend::Handled::Parked(woken, until) => {
+                    let now = Instant::now();
+                    let until = until.unwrap_or(deadline);
+                    let wait = if until > now { until - now } else { Duration::from_millis(0) };
+                    let sleep = timer.sleep(::std::cmp::min(wait, Duration::from_millis(MAX_PARK)))
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+                    woken.then(|_| Ok(())).select(sleep)
+                        .map(move |_| Loop::Continue(Instant::now() >= deadline))
+                        .map_err(|(e, _)| e)
+                        .boxed()
+                }
+            })
+        }).boxed()
     }
 }
 
-fn serve<S>(cnf: &Settings, svc: &KafkaService, factory: S) -> io::Result<()>
-    where S: NewService<Request = KafkaRequest,
-                        Response = KafkaResponse,
-                        Error = io::Error> + 'static
-{
-    let addr = cnf.listen().parse().expect("Please check the configured address and port number");
+fn serve_connection<S: AsyncRead + AsyncWrite + 'static>(stream: S, service: KafkaService, handle: &Handle) {
+    let (writer, reader) = stream.framed(KafkaCodec).split();
+    let responses = reader.and_then(move |req| service.call(req));
+    let server = writer.send_all(responses)
+        .then(|_| Ok(()));
+    handle.spawn(server);
+}
+
+fn serve(cnf: &Settings, svc: &KafkaService) -> io::Result<()> {
 	let mut core = Core::new().unwrap();
 	let handle = core.handle();
-	let listener = TcpListener::bind(&addr, &core.handle()).unwrap();
-    let connections = listener.incoming();
-    let server = connections.for_each(move |(socket, _peer_addr)| {
-        let (writer, reader) = socket.framed(KafkaCodec).split();        
-        let service = factory.new_service()?;
-        let responses = reader.and_then(move |req| service.call(req));
-        let server = writer.send_all(responses)
-            .then(|_| Ok(()));
-        handle.spawn(server);
-        Ok(())
-    });
+    let acceptor = cnf.ssl.as_ref().map(tls::acceptor);
+    let mut listeners: Vec<Box<Future<Item=(), Error=io::Error>>> = Vec::new();
+    for listener in svc.db_pool.listeners.iter() {
+        let addr = listener.bind.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+            .expect("Please check the configured address and port number");
+        let connections = TcpListener::bind(&addr, &handle).unwrap().incoming();
+        let factory = svc.clone();
+        let listener_handle = handle.clone();
+        let name = listener.name.to_string();
+        if listener.protocol.tls() {
+            let acceptor = acceptor.clone().expect("Please configure [ssl] for the SSL and SASL_SSL listeners");
+            // SASL_SSL connections authenticate with SASL, the certificate does not make the principal
+            let certificate_principal = listener.protocol == SecurityProtocol::Ssl;
+            listeners.push(Box::new(connections.for_each(move |(socket, peer_addr)| {
+                let factory = factory.clone();
+                let conn_handle = listener_handle.clone();
+                let name = name.to_string();
+                let handshake = acceptor.accept_async(socket).then(move |result| {
+                    match result {
+                        Ok(stream) => {
+                            let principal = if certificate_principal { tls::principal(stream.get_ref()) } else { None };
+                            serve_connection(stream, factory.connection(&name, peer_addr, principal), &conn_handle);
+                        },
+                        Err(e) => warn!("TLS handshake with {} failed: {}", peer_addr, e)
+                    }
+                    Ok(())
+                });
+                listener_handle.spawn(handshake);
+                Ok(())
+            })));
+        } else {
+            listeners.push(Box::new(connections.for_each(move |(socket, peer_addr)| {
+                serve_connection(socket, factory.connection(&name, peer_addr, None), &listener_handle);
+                Ok(())
+            })));
+        }
+        info!("Listener {} ({:?}) on {} advertised as {}:{}", listener.name, listener.protocol, listener.bind,
+              listener.advertised_host, listener.advertised_port);
+    }
+    let server = future::select_all(listeners)
+        .map(|(win, _, _)| win)
+        .map_err(|(e, _, _)| e);
     
     if let Some(freq) = cnf.cleanup {
 		// TODO still thinking if we need a separate event loop for this one
@@ -161,14 +268,16 @@ fn main() {
 	log4rs::init_file("config/log4rs.yaml", Default::default()).expect("Failed to initialize logging");
     let cnf = Settings::new().expect("Failed to parse the configuration file");
     debug!("Using configuraion {:?}", cnf);
+    compression::set_max_size(cnf.message_max_bytes.unwrap_or(compression::DEFAULT_MAX_SIZE));
 
 	let kafka_service = KafkaService {
         thread_pool: CpuPool::new(cnf.threads.unwrap_or(100)),
-        db_pool: backend::initialize(&cnf),
         timer: Timer::default(),
+        db_pool: backend::initialize(&cnf),
+        session: Arc::new(Mutex::new(backend::Session::new(String::new(), String::new()))), // connections get their own
     };
 	
-    if let Err(e) = serve(&cnf, &kafka_service.clone(), move || Ok(kafka_service.clone())) {
+    if let Err(e) = serve(&cnf, &kafka_service) {
         error!("UncleK failed with {}", e);
     };
 }
@@ -0,0 +1,127 @@
+// New data notifications for the parked fetches.
+// The produce path raises a NOTIFY for every partition it writes to. A single connection
+// LISTENs for them and wakes up the fetches waiting on those partitions.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use postgres::{Connection, TlsMode};
+use fallible_iterator::FallibleIterator;
+use futures::sync::oneshot;
+
+// The NOTIFY channel, the payload is "topic:partition"
+pub const CHANNEL: &'static str = "unclek_data";
+
+#[derive(Debug)]
+struct Sequences {
+    last: u64, // increases with every notification
+    partitions: HashMap<(String, u32), u64>, // the sequence of the last notification for the partition
+    parked: Vec<(Vec<(String, u32)>, oneshot::Sender<()>)> // the fetches waiting on the partitions
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    sequences: Mutex<Sequences>
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        Notifier {
+            sequences: Mutex::new(Sequences { last: 0, partitions: HashMap::new(), parked: Vec::new() })
+        }
+    }
+
+    // Take it before reading the partitions, the notifications after that point will wake up the wait
+    pub fn sequence(&self) -> u64 {
+        self.sequences.lock().unwrap().last
+    }
+
+    pub fn notify(&self, topic: &str, partition: u32) {
+        let mut sequences = self.sequences.lock().unwrap();
+        sequences.last += 1;
+        let last = sequences.last;
+        let key = (topic.to_string(), partition);
+        sequences.partitions.insert(key.clone(), last);
+        for (partitions, waiter) in mem::take(&mut sequences.parked) {
+            if partitions.contains(&key) {
+                let _ = waiter.send(()); // the fetch may be over already
+            } else if !waiter.is_canceled() {
+                sequences.parked.push((partitions, waiter));
+            }
+        }
+    }
+
+    // Parks a fetch until there is new data in any of the partitions since the sequence.
+    // The receiver completes then, the fetch drops it when its max wait is over.
+    pub fn park(&self, since: u64, partitions: Vec<(String, u32)>) -> oneshot::Receiver<()> {
+        let (waiter, woken) = oneshot::channel();
+        let mut sequences = self.sequences.lock().unwrap();
+        if partitions.iter().any(|p| sequences.partitions.get(p).map(|s| *s > since).unwrap_or(false)) {
+            let _ = waiter.send(()); // came in while the fetch was reading
+        } else {
+            sequences.parked.retain(|&(_, ref w)| !w.is_canceled());
+            sequences.parked.push((partitions, waiter));
+        }
+        woken
+    }
+}
+
+// The listener holds its own connection, LISTEN is not something to leave on the pooled ones.
+pub fn start(db_url: String, notifier: Arc<Notifier>) {
+    thread::spawn(move || loop {
+        match Connection::connect(db_url.as_str(), TlsMode::None) {
+            Ok(conn) => match conn.execute(format!("LISTEN {}", CHANNEL).as_str(), &[]) {
+                Ok(_) => {
+                    info!("Listening for the new data notifications");
+                    let notifications = conn.notifications();
+                    let mut it = notifications.blocking_iter();
+                    loop {
+                        match it.next() {
+                            Ok(Some(n)) => {
+                                let mut parts = n.payload.rsplitn(2, ':');
+                                match (parts.next().and_then(|p| p.parse().ok()), parts.next()) {
+                                    (Some(partition), Some(topic)) => notifier.notify(topic, partition),
+                                    _ => warn!("Unexpected notification payload {}", n.payload)
+                                }
+                            },
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Lost the notifications connection: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                },
+                Err(e) => error!("Failed to LISTEN for the new data: {}", e)
+            },
+            Err(e) => error!("Could not connect for the notifications: {}", e)
+        }
+        // The fetches still complete on their max wait in the meantime
+        thread::sleep(Duration::from_secs(1));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn parked_fetches_wake_up_on_their_partitions() {
+        let notifier = Notifier::new();
+        let since = notifier.sequence();
+        let woken = notifier.park(since, vec![("a".to_string(), 0), ("a".to_string(), 1)]);
+        let other = notifier.park(since, vec![("b".to_string(), 0)]);
+        let expired = notifier.park(since, vec![("c".to_string(), 0)]);
+        drop(expired); // its max wait is over
+        notifier.notify("a", 1);
+        assert!(woken.wait().is_ok());
+        assert_eq!(notifier.sequences.lock().unwrap().parked.len(), 1); // just b, c is gone
+        // The data came in while the fetch was reading
+        assert!(notifier.park(since, vec![("a".to_string(), 1)]).wait().is_ok());
+        notifier.notify("b", 0);
+        assert!(other.wait().is_ok());
+    }
+}
@@ -1,45 +1,104 @@
-use nom::{IResult,ErrorKind,be_u16,be_u32,be_u64,be_i64,be_i16,be_i32};
+use nom::{IResult,ErrorKind,Needed,be_u8,be_u16,be_u32,be_u64,be_i64,be_i8,be_i16,be_i32};
+use crc::crc32;
+use compression;
+use compression::Compression;
+use acl;
 
 // Anything that is a Kafka ApiKey request.
 #[derive(Debug)]
 pub enum ApiRequest {
     Publish {
-        acks: u16,
+        acks: i16,
         timeout: u32,
         topics: Vec<KafkaMessageSet>
     },
-    Versions,
+    Versions {
+        client_software_name: Option<String>,
+        client_software_version: Option<String>
+    },
     Metadata {
         topics: Vec<String>
     },
-    FindGroupCoordinator,
+    FindGroupCoordinator {
+        group_id: String
+    },
     JoinGroup {
         group_id: String,
+        session_timeout: u32,
+        rebalance_timeout: u32,
         member_id: String,
         protocol_type: String,
         protocols: Vec<(String, Option<Vec<u8>>)>
     },
     SyncGroup {
         group_id: String,
+        generation_id: i32,
         member_id: String,
-        assignments: Vec<Option<Vec<u8>>>
+        assignments: Vec<(String, Option<Vec<u8>>)>
     },
     FetchOffsets {
         group_id: String,
-        topics: Vec<TopicWithPartitions>
+        topics: Option<Vec<TopicWithPartitions>> // None (v2 and up) for all the offsets of the group
     },
     Offsets {
         topics: Vec<(String, Vec<(u32, i64)>)>
     },
     OffsetCommit {
-        topics: Vec<TopicWithPartitions>
+        group_id: String,
+        generation_id: i32,
+        member_id: String,
+        retention: i64,
+        topics: Vec<(String, Vec<(u32, i64, Option<String>)>)>
+    },
+    Heartbeat {
+        group_id: String,
+        generation_id: i32,
+        member_id: String
+    },
+    CreateTopics {
+        topics: Vec<NewTopic>,
+        validate_only: bool
+    },
+    DeleteTopics {
+        topics: Vec<String>
+    },
+    ListGroups,
+    DescribeGroups {
+        groups: Vec<String>
+    },
+    SaslHandshake {
+        mechanism: String
+    },
+    SaslAuthenticate {
+        auth_bytes: Vec<u8>
+    },
+    DescribeScramCredentials {
+        users: Option<Vec<String>> // None for all of them
+    },
+    AlterScramCredentials {
+        deletions: Vec<(String, i8)>, // user and mechanism
+        upsertions: Vec<ScramUpsertion>
+    },
+    DescribeAcls {
+        filter: AclFilter
+    },
+    CreateAcls {
+        creations: Vec<AclBinding>
+    },
+    DeleteAcls {
+        filters: Vec<AclFilter>
     },
-    Heartbeat,
     Unknown,
     Fetch {
-        topics: Vec<(String, Vec<(u32, u64)>)>
+        max_wait: u32,
+        min_bytes: u32,
+        max_bytes: u32, // of the whole response, unlimited before v3
+        topics: Vec<(String, Vec<(u32, u64, u32)>)> // partition, offset and max bytes
+    },
+    LeaveGroup {
+        group_id: String,
+        member_id: String
     },
-    LeaveGroup,
 }
 
 #[derive(Debug)]
@@ -57,17 +116,60 @@ pub struct KafkaRequestHeader {
 }
 
 
+#[derive(Debug)]
+pub struct ScramUpsertion {
+    pub name: String,
+    pub mechanism: i8,
+    pub iterations: i32,
+    pub salt: Vec<u8>,
+    pub salted_password: Vec<u8>
+}
+
+// The resource types, pattern types, operations and permission types are the Kafka codes in acl.rs
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclBinding {
+    pub resource_type: i8,
+    pub resource_name: String,
+    pub pattern_type: i8,
+    pub principal: String,
+    pub host: String,
+    pub operation: i8,
+    pub permission_type: i8
+}
+
+// The missing names, principals and hosts match any
+#[derive(Debug)]
+pub struct AclFilter {
+    pub resource_type: i8,
+    pub resource_name: Option<String>,
+    pub pattern_type: i8,
+    pub principal: Option<String>,
+    pub host: Option<String>,
+    pub operation: i8,
+    pub permission_type: i8
+}
+
+#[derive(Debug)]
+pub struct NewTopic {
+    pub name: String,
+    pub partitions: i32, // -1 for the default or when the assignments are given
+    pub replication_factor: i16,
+    pub assignments: Vec<i32>, // the partitions, we have no other brokers to assign them to
+    pub configs: Vec<(String, Option<String>)>
+}
+
 #[derive(Debug)]
 pub struct KafkaMessageSet {
     pub topic: String,
-    pub messages: Vec<(u32, Vec<KafkaMessage>)>
+    pub messages: Vec<(u32, Option<Vec<KafkaMessage>>)> // None when the message set of the partition is corrupt
 }
 
 #[derive(Debug)]
 pub struct KafkaMessage {
-    timestamp: u64,
+    pub timestamp: i64,
     pub key: Option<Vec<u8> >,
-    pub value: Option<Vec<u8> >
+    pub value: Option<Vec<u8> >,
+    pub headers: Vec<(String, Option<Vec<u8>>)>
 }
 
 #[derive(Debug, Clone)]
@@ -106,6 +208,59 @@ named!(opt_kafka_bytes<&[u8], Option<Vec<u8> > >,
 );
 
 
+// Unsigned varint of the flexible versions
+fn unsigned_varint(input: &[u8]) -> IResult<&[u8], u64> {
+    let mut value: u64 = 0;
+    for (i, b) in input.iter().enumerate().take(10) {
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return IResult::Done(&input[i + 1..], value);
+        }
+    }
+    if input.len() < 10 {
+        IResult::Incomplete(Needed::Unknown)
+    } else {
+        IResult::Error(error_code!(ErrorKind::Custom(2)))
+    }
+}
+
+// Zigzag encoded varint/varlong of the record batch v2
+named!(varint<&[u8], i64>, map!(unsigned_varint, |v| ((v >> 1) as i64) ^ -((v & 1) as i64)));
+
+named!(varint_count<&[u8], usize>, map!(varint, |c| if c < 0 { 0 } else { c as usize }));
+
+named!(varint_bytes<&[u8], Option<Vec<u8> > >, do_parse!(
+    length: varint >>
+    bytes:  cond!(length >= 0, take!(length as usize)) >>
+    (bytes.map(|b| b.to_vec()))
+));
+
+named!(varint_string<&[u8], String>, map!(
+    length_bytes!(varint_count), kafka_string
+));
+
+// Compact strings of the flexible versions carry the length + 1, 0 is null
+named!(compact_string<&[u8], Option<String> >, do_parse!(
+    length: unsigned_varint >>
+    string: cond!(length > 0, map!(take!((length - 1) as usize), kafka_string)) >>
+    (string)
+));
+
+// Compact arrays carry the count + 1, 0 is null
+named!(compact_count<&[u8], usize>, map!(unsigned_varint, |c| if c == 0 { 0 } else { (c - 1) as usize }));
+
+named!(compact_bytes<&[u8], Vec<u8> >, map!(length_bytes!(compact_count), |b: &[u8]| b.to_vec()));
+
+// We do not know any tagged fields yet, skip them
+named!(tagged_fields<&[u8], ()>, do_parse!(
+    length_count!(unsigned_varint, do_parse!(
+        /*tag*/ unsigned_varint >>
+        /*data*/ length_bytes!(unsigned_varint) >>
+        ()
+    )) >>
+    ()
+));
+
 fn request_header(input:&[u8]) -> IResult<&[u8], KafkaRequestHeader> {
   do_parse!(input,
     opcode: be_i16 >>
@@ -124,7 +279,37 @@ fn request_header(input:&[u8]) -> IResult<&[u8], KafkaRequestHeader> {
 }
 
 fn versions(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
-    IResult::Done(input, KafkaRequest{header: header, req: ApiRequest::Versions})
+    let version = header.version;
+    do_parse!(input,
+      /*header tags*/       cond!(version >= 3, tagged_fields) >> // v3 is the first flexible version
+      software_name:        cond!(version >= 3, compact_string) >>
+      software_version:     cond!(version >= 3, compact_string) >>
+      /*tags*/              cond!(version >= 3, tagged_fields) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::Versions {
+            client_software_name: software_name.and_then(|s| s),
+            client_software_version: software_version.and_then(|s| s)
+        }
+      }
+    )
+   )
+}
+
+// A client newer than us learns our versions from the error response. Its request body is not ours to read.
+fn unsupported_versions(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    IResult::Done(&input[input.len()..], KafkaRequest{header: header, req: ApiRequest::Versions {
+        client_software_name: None,
+        client_software_version: None
+    }})
+}
+
+fn find_coordinator(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      group_id:             map!(length_bytes!(be_u16), kafka_string) >>
+      (KafkaRequest{header: header, req: ApiRequest::FindGroupCoordinator { group_id: group_id }})
+    )
 }
 
 fn metadata(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
@@ -147,7 +332,8 @@ fn metadata(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaReque
 
 fn publish(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     do_parse!(input,
-      acks: be_u16 >>
+      /*transactional_id*/  cond!(header.version >= 3, opt_kafka_string) >>
+      acks: be_i16 >>
       timeout: be_u32 >>
       topics: length_count!(be_u32, publish_topic) >>
     (
@@ -177,30 +363,155 @@ named!(publish_topic<&[u8], KafkaMessageSet>, do_parse!(
     )
 ));
 
-fn message_set(input: &[u8]) -> IResult<&[u8], Vec<KafkaMessage>> {
-    many0!(input, do_parse!(
+// A message that fails to parse or to decompress makes the whole set corrupt, none of it gets written
+fn message_set(input: &[u8]) -> IResult<&[u8], Option<Vec<KafkaMessage>>> {
+    let mut messages = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        match message(rest) {
+            IResult::Done(tail, Some(unwrapped)) => {
+                messages.extend(unwrapped);
+                rest = tail;
+            },
+            _ => return IResult::Done(&rest[rest.len()..], None)
+        }
+    }
+    IResult::Done(rest, Some(messages))
+}
+
+// The magic byte is at the same position in the old messages and in the record batches
+fn message(input: &[u8]) -> IResult<&[u8], Option<Vec<KafkaMessage>>> {
+    if input.len() > 16 && input[16] == 2 {
+        record_batch(input)
+    } else {
+        legacy_message(input)
+    }
+}
+
+// A single message, or all the messages of a compressed wrapper message
+fn legacy_message(input: &[u8]) -> IResult<&[u8], Option<Vec<KafkaMessage>>> {
+    do_parse!(input,
         /*offset */    be_u64 >>
         /*msg bytes*/  be_u32 >>
         /*crc */       be_u32 >> // TODO: we'll need this eventually
-        /*magic */     tag!([1]) >>
-        /*attributes*/ tag!([0]) >> // TODO: we'll need to parse it
-        timestamp:     be_u64 >>
+        magic:         verify!(be_u8, |m: u8| m <= 1) >>
+        attributes:    be_u8 >>
+        timestamp:     cond!(magic == 1, be_i64) >> // magic 0 has no timestamps
         key:           opt_kafka_bytes >>
         value:         opt_kafka_bytes >>
+        (unwrap_message(attributes, timestamp.unwrap_or(-1), key, value))
+    )
+}
+
+// Record batch, magic 2
+fn record_batch(input: &[u8]) -> IResult<&[u8], Option<Vec<KafkaMessage>>> {
+    do_parse!(input,
+        /*base_offset*/ be_u64 >>
+        batch:          length_bytes!(be_u32) >>
+        (unwrap_batch(batch))
+    )
+}
+
+fn unwrap_batch(batch: &[u8]) -> Option<Vec<KafkaMessage>> {
+    // partition leader epoch, magic and crc come before the checksummed part
+    if batch.len() < 9 || crc32::checksum_castagnoli(&batch[9..]) != batch[5..9].iter().fold(0u32, |acc, b| (acc << 8) | *b as u32) {
+        warn!("Record batch CRC mismatch");
+        return None;
+    }
+    let parsed = do_parse!(&batch[9..],
+        attributes:            be_i16 >>
+        /*last_offset_delta*/  be_i32 >>
+        first_timestamp:       be_i64 >>
+        /*max_timestamp*/      be_i64 >>
+        /*producer_id*/        be_i64 >>
+        /*producer_epoch*/     be_i16 >>
+        /*base_sequence*/      be_i32 >>
+        count:                 be_u32 >>
+        ((attributes, first_timestamp, count))
+    );
+    let (records, (attributes, first_timestamp, count)) = match parsed {
+        IResult::Done(records, header) => (records, header),
+        _ => return None
+    };
+    let records = match Compression::from_attributes(attributes as u8) {
+        Some(Compression::None) => records.to_vec(),
+        Some(codec) => match compression::decompress(codec, records) {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("Failed to decompress a {:?} record batch: {}", codec, e);
+                return None;
+            }
+        },
+        None => {
+            warn!("Unsupported compression codec in attributes {}", attributes);
+            return None;
+        }
+    };
+    // All the records must be there, and nothing else
+    if let IResult::Done(rest, messages) = count!(&records[..], call!(record, first_timestamp), count as usize) {
+        if rest.is_empty() {
+            return Some(messages);
+        }
+    }
+    warn!("Record batch does not hold the {} records it claims", count);
+    None
+}
+
+fn record(input: &[u8], first_timestamp: i64) -> IResult<&[u8], KafkaMessage> {
+    flat_map!(input, length_bytes!(varint_count), do_parse!(
+        /*attributes*/    be_u8 >>
+        timestamp_delta:  varint >>
+        /*offset_delta*/  varint >>
+        key:              varint_bytes >>
+        value:            varint_bytes >>
+        headers:          length_count!(varint_count, do_parse!(
+            name:           varint_string >>
+            value:          varint_bytes >>
+                            ((name, value))
+                          )) >>
         (
           KafkaMessage {
-            timestamp: timestamp,
+            timestamp: first_timestamp + timestamp_delta,
             key: key,
-            value: value
+            value: value,
+            headers: headers
           }
         )
     ))
 }
 
+fn unwrap_message(attributes: u8, timestamp: i64, key: Option<Vec<u8>>, value: Option<Vec<u8>>) -> Option<Vec<KafkaMessage>> {
+    match Compression::from_attributes(attributes) {
+        Some(Compression::None) => Some(vec![KafkaMessage {
+            timestamp: timestamp,
+            key: key,
+            value: value,
+            headers: Vec::new()
+        }]),
+        Some(codec) => {
+            let inner = match compression::decompress(codec, &value.unwrap_or_default()) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    warn!("Failed to decompress a {:?} message set: {}", codec, e);
+                    return None;
+                }
+            };
+            match message_set(&inner) {
+                IResult::Done(_, messages) => messages,
+                _ => None
+            }
+        },
+        None => {
+            warn!("Unsupported compression codec in attributes {}", attributes);
+            None
+        }
+    }
+}
+
 fn join_group0(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     do_parse!(input,
       group_id:             map!(length_bytes!(be_u16), kafka_string) >>
-      /*session_timeout*/   be_u32 >>
+      session_timeout:      be_u32 >>
       member_id:            map!(length_bytes!(be_u16), kafka_string) >>
       protocol_type:        map!(length_bytes!(be_u16), kafka_string) >>
       protocols:            length_count!(be_u32, do_parse!(
@@ -213,6 +524,8 @@ fn join_group0(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRe
         header: header,
         req: ApiRequest::JoinGroup {
             group_id: group_id,
+            session_timeout: session_timeout,
+            rebalance_timeout: session_timeout, // v0 has no separate rebalance timeout
             member_id: member_id,
             protocol_type: protocol_type,
             protocols: protocols
@@ -225,8 +538,8 @@ fn join_group0(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRe
 fn join_group1(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     do_parse!(input,
       group_id:             map!(length_bytes!(be_u16), kafka_string) >>
-      /*session_timeout*/   be_u32 >>
-      /*rebalance_timeout*/ be_u32 >>
+      session_timeout:      be_u32 >>
+      rebalance_timeout:    be_u32 >>
       member_id:            map!(length_bytes!(be_u16), kafka_string) >>
       protocol_type:        map!(length_bytes!(be_u16), kafka_string) >>
       protocols:            length_count!(be_u32, do_parse!(
@@ -239,6 +552,8 @@ fn join_group1(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRe
         header: header,
         req: ApiRequest::JoinGroup {
             group_id: group_id,
+            session_timeout: session_timeout,
+            rebalance_timeout: rebalance_timeout,
             member_id: member_id,
             protocol_type: protocol_type,
             protocols: protocols
@@ -251,18 +566,19 @@ fn join_group1(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRe
 fn sync_group(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     do_parse!(input,
       group_id:             map!(length_bytes!(be_u16), kafka_string) >>
-      /*generation_id*/     be_u32 >>
+      generation_id:        be_i32 >>
       member_id:            map!(length_bytes!(be_u16), kafka_string) >>
       assignments:          length_count!(be_u32, do_parse!(
-                              map!(length_bytes!(be_u16), kafka_string) >>
+          member:             map!(length_bytes!(be_u16), kafka_string) >>
           assignment:         opt_kafka_bytes >>
-                              (assignment)
+                              ((member, assignment))
                             )) >>
     (
       KafkaRequest {
         header: header,
         req: ApiRequest::SyncGroup {
             group_id: group_id,
+            generation_id: generation_id,
             member_id: member_id,
             assignments: assignments
         }
@@ -271,14 +587,242 @@ fn sync_group(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaReq
    )
 }
 
-fn fetch_offset(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+fn heartbeat(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     do_parse!(input,
       group_id:             map!(length_bytes!(be_u16), kafka_string) >>
-      topics:               length_count!(be_u32, do_parse!(
-          topic:              map!(length_bytes!(be_u16), kafka_string) >>
-          partitions:         length_count!(be_u32, be_u32) >>
-                              (TopicWithPartitions::new(topic, partitions))
+      generation_id:        be_i32 >>
+      member_id:            map!(length_bytes!(be_u16), kafka_string) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::Heartbeat {
+            group_id: group_id,
+            generation_id: generation_id,
+            member_id: member_id
+        }
+      }
+    )
+   )
+}
+
+fn leave_group(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      group_id:             map!(length_bytes!(be_u16), kafka_string) >>
+      member_id:            map!(length_bytes!(be_u16), kafka_string) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::LeaveGroup {
+            group_id: group_id,
+            member_id: member_id
+        }
+      }
+    )
+   )
+}
+
+fn describe_groups(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      groups:               length_count!(be_u32, map!(length_bytes!(be_u16), kafka_string)) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::DescribeGroups {
+            groups: groups
+        }
+      }
+    )
+   )
+}
+
+fn list_groups(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    IResult::Done(input, KafkaRequest{header: header, req: ApiRequest::ListGroups})
+}
+
+fn sasl_handshake(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      mechanism:            map!(length_bytes!(be_u16), kafka_string) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::SaslHandshake {
+            mechanism: mechanism
+        }
+      }
+    )
+   )
+}
+
+fn sasl_authenticate(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      auth_bytes:           length_bytes!(be_u32) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::SaslAuthenticate {
+            auth_bytes: auth_bytes.to_vec()
+        }
+      }
+    )
+   )
+}
+
+// Both UserScramCredentials requests only have flexible versions
+fn describe_scram_credentials(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      /*header tags*/       tagged_fields >>
+      count:                unsigned_varint >>
+      users:                count!(do_parse!(
+                                name: compact_string >>
+                                      tagged_fields >>
+                                (name.unwrap_or_default())
+                            ), if count == 0 { 0 } else { (count - 1) as usize }) >>
+      /*tags*/              tagged_fields >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::DescribeScramCredentials {
+            users: if count == 0 { None } else { Some(users) }
+        }
+      }
+    )
+   )
+}
+
+fn alter_scram_credentials(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      /*header tags*/       tagged_fields >>
+      deletions:            length_count!(compact_count, do_parse!(
+                                name:      compact_string >>
+                                mechanism: be_i8 >>
+                                           tagged_fields >>
+                                ((name.unwrap_or_default(), mechanism))
+                            )) >>
+      upsertions:           length_count!(compact_count, do_parse!(
+                                name:            compact_string >>
+                                mechanism:       be_i8 >>
+                                iterations:      be_i32 >>
+                                salt:            compact_bytes >>
+                                salted_password: compact_bytes >>
+                                                 tagged_fields >>
+                                (ScramUpsertion {
+                                    name: name.unwrap_or_default(),
+                                    mechanism: mechanism,
+                                    iterations: iterations,
+                                    salt: salt,
+                                    salted_password: salted_password
+                                })
+                            )) >>
+      /*tags*/              tagged_fields >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::AlterScramCredentials {
+            deletions: deletions,
+            upsertions: upsertions
+        }
+      }
+    )
+   )
+}
+
+// v0 has no pattern type, the ACLs are all literal
+fn acl_filter(input:&[u8], version: i16) -> IResult<&[u8], AclFilter> {
+    do_parse!(input,
+      resource_type:        be_i8 >>
+      resource_name:        opt_kafka_string >>
+      pattern_type:         cond!(version >= 1, be_i8) >>
+      principal:            opt_kafka_string >>
+      host:                 opt_kafka_string >>
+      operation:            be_i8 >>
+      permission_type:      be_i8 >>
+    (
+      AclFilter {
+          resource_type: resource_type,
+          resource_name: resource_name,
+          pattern_type: pattern_type.unwrap_or(acl::LITERAL),
+          principal: principal,
+          host: host,
+          operation: operation,
+          permission_type: permission_type
+      }
+    )
+   )
+}
+
+fn describe_acls(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    let version = header.version;
+    do_parse!(input,
+      filter:               call!(acl_filter, version) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::DescribeAcls {
+            filter: filter
+        }
+      }
+    )
+   )
+}
+
+fn create_acls(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    let version = header.version;
+    do_parse!(input,
+      creations:            length_count!(be_u32, do_parse!(
+        resource_type:        be_i8 >>
+        resource_name:        map!(length_bytes!(be_u16), kafka_string) >>
+        pattern_type:         cond!(version >= 1, be_i8) >>
+        principal:            map!(length_bytes!(be_u16), kafka_string) >>
+        host:                 map!(length_bytes!(be_u16), kafka_string) >>
+        operation:            be_i8 >>
+        permission_type:      be_i8 >>
+                              (AclBinding {
+                                  resource_type: resource_type,
+                                  resource_name: resource_name,
+                                  pattern_type: pattern_type.unwrap_or(acl::LITERAL),
+                                  principal: principal,
+                                  host: host,
+                                  operation: operation,
+                                  permission_type: permission_type
+                              })
                             )) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::CreateAcls {
+            creations: creations
+        }
+      }
+    )
+   )
+}
+
+fn delete_acls(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    let version = header.version;
+    do_parse!(input,
+      filters:              length_count!(be_u32, call!(acl_filter, version)) >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::DeleteAcls {
+            filters: filters
+        }
+      }
+    )
+   )
+}
+
+fn fetch_offset(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      group_id:             map!(length_bytes!(be_u16), kafka_string) >>
+      topics:               alt!(
+          tag!([0xff, 0xff, 0xff, 0xff]) => { |_| None } |
+          length_count!(be_u32, do_parse!(
+            topic:              map!(length_bytes!(be_u16), kafka_string) >>
+            partitions:         length_count!(be_u32, be_u32) >>
+                                (TopicWithPartitions::new(topic, partitions))
+                              )) => { |t| Some(t) }
+      ) >>
     (
       KafkaRequest {
         header: header,
@@ -340,24 +884,28 @@ fn offsets1(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaReque
 
 fn offset_commit(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     do_parse!(input,
-      /*group_id*/          length_bytes!(be_u16) >>
-      /*generation*/        be_u32 >>
-      /*member*/            length_bytes!(be_u16) >>
-      /*retention*/         be_u64 >>
+      group_id:             map!(length_bytes!(be_u16), kafka_string) >>
+      generation_id:        be_i32 >>
+      member_id:            map!(length_bytes!(be_u16), kafka_string) >>
+      retention:            be_i64 >>
       topics:               length_count!(be_u32, do_parse!(
         topic:                map!(length_bytes!(be_u16), kafka_string) >>
         partitions:           length_count!(be_u32, do_parse!(
           partition:            be_u32 >>
-          /*offset*/            be_u64 >>
-          /*meta*/              opt_kafka_string >>
-                                (partition)
+          offset:               be_i64 >>
+          metadata:             opt_kafka_string >>
+                                ((partition, offset, metadata))
                               )) >>
-                              (TopicWithPartitions::new(topic, partitions))
+                              ((topic, partitions))
                             )) >>
     (
       KafkaRequest {
         header: header,
         req: ApiRequest::OffsetCommit {
+            group_id: group_id,
+            generation_id: generation_id,
+            member_id: member_id,
+            retention: retention,
             topics: topics
         }
       }
@@ -365,25 +913,53 @@ fn offset_commit(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], Kafka
    )
 }
 
-fn fetch2(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+fn create_topics(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    let version = header.version;
     do_parse!(input,
-      /*replica_id*/        be_u32 >>
-      /*max wait*/          be_u32 >>
-      /*min bytes*/         be_u32 >>
       topics:               length_count!(be_u32, do_parse!(
-        topic:                map!(length_bytes!(be_u16), kafka_string) >>
-        partitions:           length_count!(be_u32, do_parse!(
-          partition:            be_u32 >>
-          offset:               be_u64 >>
-          /*max bytes*/         be_u32 >>
-                                ((partition, offset))
+        name:                 map!(length_bytes!(be_u16), kafka_string) >>
+        partitions:           be_i32 >>
+        replication_factor:   be_i16 >>
+        assignments:          length_count!(be_u32, do_parse!(
+          partition:            be_i32 >>
+          /*broker ids*/        length_count!(be_u32, be_i32) >>
+                                (partition)
                               )) >>
-                              ((topic, partitions))
+        configs:              length_count!(be_u32, do_parse!(
+          name:                 map!(length_bytes!(be_u16), kafka_string) >>
+          value:                opt_kafka_string >>
+                                ((name, value))
+                              )) >>
+                              (NewTopic {
+                                  name: name,
+                                  partitions: partitions,
+                                  replication_factor: replication_factor,
+                                  assignments: assignments,
+                                  configs: configs
+                              })
                             )) >>
+      /*timeout*/           be_i32 >> // the tables are created before we answer anyway
+      validate_only:        cond!(version >= 1, be_u8) >>
     (
       KafkaRequest {
         header: header,
-        req: ApiRequest::Fetch {
+        req: ApiRequest::CreateTopics {
+            topics: topics,
+            validate_only: validate_only.unwrap_or(0) != 0
+        }
+      }
+    )
+   )
+}
+
+fn delete_topics(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    do_parse!(input,
+      topics:               length_count!(be_u32, map!(length_bytes!(be_u16), kafka_string)) >>
+      /*timeout*/           be_i32 >>
+    (
+      KafkaRequest {
+        header: header,
+        req: ApiRequest::DeleteTopics {
             topics: topics
         }
       }
@@ -391,26 +967,40 @@ fn fetch2(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest
    )
 }
 
-fn fetch3(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+fn fetch(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest> {
+    let version = header.version;
     do_parse!(input,
       /*replica_id*/        be_u32 >>
-      /*max wait*/          be_u32 >>
-      /*min bytes*/         be_u32 >>
-      /*max bytes*/         be_u32 >>
+      max_wait:             be_u32 >>
+      min_bytes:            be_u32 >>
+      max_bytes:            cond!(version >= 3, be_u32) >>
+      /*isolation level*/   cond!(version >= 4, be_u8) >>
+      /*session id, epoch*/ cond!(version >= 7, tuple!(be_i32, be_i32)) >>
       topics:               length_count!(be_u32, do_parse!(
         topic:                map!(length_bytes!(be_u16), kafka_string) >>
         partitions:           length_count!(be_u32, do_parse!(
           partition:            be_u32 >>
+          /*leader epoch*/      cond!(version >= 9, be_i32) >>
           offset:               be_u64 >>
-          /*max bytes*/         be_u32 >>
-                                ((partition, offset))
+          /*log start offset*/  cond!(version >= 5, be_i64) >>
+          max_bytes:            be_u32 >>
+                                ((partition, offset, max_bytes))
                               )) >>
                               ((topic, partitions))
                             )) >>
+      /*forgotten topics*/  cond!(version >= 7, length_count!(be_u32, do_parse!(
+                              length_bytes!(be_u16) >>
+                              length_count!(be_u32, be_u32) >>
+                              ()
+                            ))) >>
+      /*rack id*/           cond!(version >= 11, length_bytes!(be_u16)) >>
     (
       KafkaRequest {
         header: header,
         req: ApiRequest::Fetch {
+            max_wait: max_wait,
+            min_bytes: min_bytes,
+            max_bytes: max_bytes.unwrap_or(::std::u32::MAX),
             topics: topics
         }
       }
@@ -419,25 +1009,63 @@ fn fetch3(header:KafkaRequestHeader, input:&[u8]) -> IResult<&[u8], KafkaRequest
 }
 
 
+type RequestParser = fn(KafkaRequestHeader, &[u8]) -> IResult<&[u8], KafkaRequest>;
+
+// Every request we can parse: opcode, min and max version and the parser.
+// The ApiVersions response is built from the same list, so we never advertise what we cannot read.
+const REQUESTS: &'static [(i16, i16, i16, RequestParser)] = &[
+    ( 0, 0,  7, publish),
+    ( 1, 0, 11, fetch),
+    ( 2, 0,  0, offsets0),
+    ( 2, 1,  1, offsets1),
+    ( 3, 2,  2, metadata),
+    ( 8, 2,  2, offset_commit),
+    ( 9, 0,  2, fetch_offset),
+    (10, 0,  0, find_coordinator),
+    (11, 0,  0, join_group0),
+    (11, 1,  1, join_group1),
+    (12, 0,  0, heartbeat),
+    (13, 0,  0, leave_group),
+    (14, 0,  0, sync_group),
+    (15, 0,  2, describe_groups),
+    (16, 0,  2, list_groups),
+    (17, 1,  1, sasl_handshake), // v0 sends the SASL tokens without the Kafka framing, not supported
+    (18, 0,  3, versions),
+    (19, 0,  4, create_topics),
+    (20, 0,  3, delete_topics),
+    (29, 0,  1, describe_acls),
+    (30, 0,  1, create_acls),
+    (31, 0,  1, delete_acls),
+    (36, 0,  1, sasl_authenticate),
+    (50, 0,  0, describe_scram_credentials),
+    (51, 0,  0, alter_scram_credentials),
+];
+
+// The (opcode, min version, max version) of every supported request, the consecutive versions merged
+pub fn supported_versions() -> Vec<(i16, i16, i16)> {
+    let mut supported: Vec<(i16, i16, i16)> = Vec::new();
+    for &(opcode, min, max, _) in REQUESTS {
+        match supported.last_mut() {
+            Some(ref mut last) if last.0 == opcode && last.2 + 1 == min => last.2 = max,
+            _ => supported.push((opcode, min, max))
+        }
+    }
+    supported
+}
+
+pub fn is_supported(opcode: i16, version: i16) -> bool {
+    REQUESTS.iter().any(|r| r.0 == opcode && r.1 <= version && version <= r.2)
+}
+
 pub fn kafka_request(input:&[u8]) -> IResult<&[u8], KafkaRequest> {
     if let IResult::Done(tail, req) = request_header(input) {
-        match req {
-           KafkaRequestHeader {opcode: 0, version: 2, .. } => publish(req, tail),
-           KafkaRequestHeader {opcode: 1, version: 2, .. } => fetch2(req, tail),
-           KafkaRequestHeader {opcode: 1, version: 3, .. } => fetch3(req, tail),
-           KafkaRequestHeader {opcode: 2, version: 0, .. } => offsets0(req, tail),
-           KafkaRequestHeader {opcode: 2, version: 1, .. } => offsets1(req, tail),
-           KafkaRequestHeader {opcode: 3, version: 2, .. } => metadata(req, tail),
-           KafkaRequestHeader {opcode: 8, version: 2, .. } => offset_commit(req, tail),
-           KafkaRequestHeader {opcode: 9, .. }             => fetch_offset(req, tail),
-           KafkaRequestHeader {opcode:10, version: 0, .. } => IResult::Done(input, KafkaRequest{header: req, req: ApiRequest::FindGroupCoordinator}),
-           KafkaRequestHeader {opcode:11, version: 0, .. } => join_group0(req, tail),
-           KafkaRequestHeader {opcode:11, version: 1, .. } => join_group1(req, tail),
-           KafkaRequestHeader {opcode:12, version: 0, .. } => IResult::Done(input, KafkaRequest{header: req, req: ApiRequest::Heartbeat}),
-           KafkaRequestHeader {opcode:13, version: 0, .. } => IResult::Done(input, KafkaRequest{header: req, req: ApiRequest::LeaveGroup}),
-           KafkaRequestHeader {opcode:14, version: 0, .. } => sync_group(req, tail),
-           KafkaRequestHeader {opcode:18, version: 0, .. } => versions(req, tail),
-           _ => {
+        let parser = REQUESTS.iter()
+            .find(|r| r.0 == req.opcode && r.1 <= req.version && req.version <= r.2)
+            .map(|r| r.3);
+        match parser {
+           Some(parser) => parser(req, tail),
+           None if req.opcode == 18 => unsupported_versions(req, tail),
+           None => {
                warn!("Not yet implemented request {:?}", req);
                IResult::Done(input, KafkaRequest{header: req, req: ApiRequest::Unknown})
                // IResult::Error(error_code!(ErrorKind::Custom(1)))
@@ -448,3 +1076,377 @@ pub fn kafka_request(input:&[u8]) -> IResult<&[u8], KafkaRequest> {
         IResult::Error(error_code!(ErrorKind::Custom(0)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BytesMut, BufMut, BigEndian};
+
+    fn put_string(s: &str, out: &mut BytesMut) {
+        out.put_i16::<BigEndian>(s.len() as i16);
+        out.put_slice(s.as_bytes());
+    }
+
+    // The smallest sensible request of the version: a topic with a partition wherever the request has them
+    fn request(opcode: i16, version: i16) -> BytesMut {
+        let mut out = BytesMut::with_capacity(1024);
+        out.put_i16::<BigEndian>(opcode);
+        out.put_i16::<BigEndian>(version);
+        out.put_i32::<BigEndian>(42); // correlation id
+        put_string("test", &mut out); // client id
+        match opcode {
+            0 => {
+                if version >= 3 {
+                    out.put_i16::<BigEndian>(-1); // transactional id
+                }
+                out.put_i16::<BigEndian>(1); // acks
+                out.put_i32::<BigEndian>(1000); // timeout
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+                out.put_i32::<BigEndian>(1);
+                out.put_i32::<BigEndian>(0); // partition
+                out.put_i32::<BigEndian>(0); // empty message set
+            },
+            1 => {
+                out.put_i32::<BigEndian>(-1); // replica id
+                out.put_i32::<BigEndian>(500); // max wait
+                out.put_i32::<BigEndian>(1); // min bytes
+                if version >= 3 {
+                    out.put_i32::<BigEndian>(1024 * 1024); // max bytes
+                }
+                if version >= 4 {
+                    out.put_u8(0); // isolation level
+                }
+                if version >= 7 {
+                    out.put_i32::<BigEndian>(0); // session id
+                    out.put_i32::<BigEndian>(-1); // session epoch
+                }
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+                out.put_i32::<BigEndian>(1);
+                out.put_i32::<BigEndian>(0); // partition
+                if version >= 9 {
+                    out.put_i32::<BigEndian>(-1); // leader epoch
+                }
+                out.put_i64::<BigEndian>(0); // offset
+                if version >= 5 {
+                    out.put_i64::<BigEndian>(-1); // log start offset
+                }
+                out.put_i32::<BigEndian>(1024 * 1024); // partition max bytes
+                if version >= 7 {
+                    out.put_i32::<BigEndian>(0); // forgotten topics
+                }
+                if version >= 11 {
+                    put_string("", &mut out); // rack id
+                }
+            },
+            2 => {
+                out.put_i32::<BigEndian>(-1); // replica id
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+                out.put_i32::<BigEndian>(1);
+                out.put_i32::<BigEndian>(0); // partition
+                out.put_i64::<BigEndian>(-1); // timestamp
+                if version == 0 {
+                    out.put_i32::<BigEndian>(1); // max number of offsets
+                }
+            },
+            3 => {
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+            },
+            8 => {
+                put_string("g", &mut out);
+                out.put_i32::<BigEndian>(1); // generation
+                put_string("m", &mut out);
+                out.put_i64::<BigEndian>(-1); // retention
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+                out.put_i32::<BigEndian>(1);
+                out.put_i32::<BigEndian>(0); // partition
+                out.put_i64::<BigEndian>(0); // offset
+                put_string("", &mut out); // metadata
+            },
+            9 => {
+                put_string("g", &mut out);
+                if version >= 2 {
+                    out.put_i32::<BigEndian>(-1); // all the topics, the way kafka-consumer-groups.sh asks
+                } else {
+                    out.put_i32::<BigEndian>(1);
+                    put_string("t", &mut out);
+                    out.put_i32::<BigEndian>(1);
+                    out.put_i32::<BigEndian>(0); // partition
+                }
+            },
+            10 => put_string("g", &mut out),
+            11 => {
+                put_string("g", &mut out);
+                out.put_i32::<BigEndian>(10000); // session timeout
+                if version >= 1 {
+                    out.put_i32::<BigEndian>(30000); // rebalance timeout
+                }
+                put_string("", &mut out); // member id
+                put_string("consumer", &mut out);
+                out.put_i32::<BigEndian>(1);
+                put_string("range", &mut out);
+                out.put_i32::<BigEndian>(0); // metadata
+            },
+            12 => {
+                put_string("g", &mut out);
+                out.put_i32::<BigEndian>(1); // generation
+                put_string("m", &mut out);
+            },
+            13 => {
+                put_string("g", &mut out);
+                put_string("m", &mut out);
+            },
+            14 => {
+                put_string("g", &mut out);
+                out.put_i32::<BigEndian>(1); // generation
+                put_string("m", &mut out);
+                out.put_i32::<BigEndian>(1);
+                put_string("m", &mut out);
+                out.put_i32::<BigEndian>(0); // assignment
+            },
+            15 => {
+                out.put_i32::<BigEndian>(1);
+                put_string("g", &mut out);
+            },
+            16 => (),
+            17 => put_string("PLAIN", &mut out),
+            18 => {
+                if version >= 3 {
+                    out.put_u8(0); // header tagged fields
+                    out.put_u8(5); // client software name
+                    out.put_slice(b"test");
+                    out.put_u8(6); // client software version
+                    out.put_slice(b"1.0.0");
+                    out.put_u8(0); // tagged fields
+                }
+            },
+            19 => {
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+                out.put_i32::<BigEndian>(-1); // partitions
+                out.put_i16::<BigEndian>(-1); // replication factor
+                out.put_i32::<BigEndian>(1);
+                out.put_i32::<BigEndian>(0); // partition
+                out.put_i32::<BigEndian>(1);
+                out.put_i32::<BigEndian>(0); // broker id
+                out.put_i32::<BigEndian>(1);
+                put_string("cleanup.policy", &mut out);
+                put_string("compact", &mut out);
+                out.put_i32::<BigEndian>(1000); // timeout
+                if version >= 1 {
+                    out.put_u8(1); // validate only
+                }
+            },
+            20 => {
+                out.put_i32::<BigEndian>(1);
+                put_string("t", &mut out);
+                out.put_i32::<BigEndian>(1000); // timeout
+            },
+            29 => acl_sample(version, true, &mut out),
+            30 => {
+                out.put_i32::<BigEndian>(1);
+                acl_sample(version, false, &mut out);
+            },
+            31 => {
+                out.put_i32::<BigEndian>(1);
+                acl_sample(version, true, &mut out);
+            },
+            36 => {
+                out.put_i32::<BigEndian>(11);
+                out.put_slice(b"\0alice\0pass");
+            },
+            50 => {
+                out.put_u8(0); // header tags
+                out.put_u8(2); // one user
+                out.put_u8(6);
+                out.put_slice(b"alice");
+                out.put_u8(0); // tags
+                out.put_u8(0); // tags
+            },
+            51 => {
+                out.put_u8(0); // header tags
+                out.put_u8(2); // one deletion
+                out.put_u8(4);
+                out.put_slice(b"bob");
+                out.put_i8(1); // SCRAM-SHA-256
+                out.put_u8(0); // tags
+                out.put_u8(2); // one upsertion
+                out.put_u8(6);
+                out.put_slice(b"alice");
+                out.put_i8(2); // SCRAM-SHA-512
+                out.put_i32::<BigEndian>(4096);
+                out.put_u8(3);
+                out.put_slice(b"ab"); // salt
+                out.put_u8(3);
+                out.put_slice(b"cd"); // salted password
+                out.put_u8(0); // tags
+                out.put_u8(0); // tags
+            },
+            _ => panic!("No sample request for opcode {}", opcode)
+        }
+        out
+    }
+
+    // Allow User:alice to read topic t from any host, the filters leave the principal out
+    fn acl_sample(version: i16, filter: bool, out: &mut BytesMut) {
+        out.put_i8(2); // topic
+        put_string("t", out);
+        if version >= 1 {
+            out.put_i8(3); // literal
+        }
+        if filter {
+            out.put_i16::<BigEndian>(-1);
+        } else {
+            put_string("User:alice", out);
+        }
+        put_string("*", out);
+        out.put_i8(3); // read
+        out.put_i8(3); // allow
+    }
+
+    #[test]
+    fn every_advertised_version_parses() {
+        for (opcode, min, max) in supported_versions() {
+            for version in min..max + 1 {
+                let input = request(opcode, version);
+                match kafka_request(&input[..]) {
+                    IResult::Done(tail, KafkaRequest { ref req, .. }) => {
+                        match *req {
+                            ApiRequest::Unknown => panic!("Opcode {} version {} is advertised but not dispatched", opcode, version),
+                            _ => assert!(tail.is_empty(), "Opcode {} version {} left {} bytes", opcode, version, tail.len())
+                        }
+                    },
+                    other => panic!("Opcode {} version {} failed to parse {:?}", opcode, version, other)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn versions_v3_reports_the_client_software() {
+        match kafka_request(&request(18, 3)[..]) {
+            IResult::Done(_, KafkaRequest { req: ApiRequest::Versions { client_software_name, client_software_version }, .. }) => {
+                assert_eq!(client_software_name, Some("test".to_string()));
+                assert_eq!(client_software_version, Some("1.0.0".to_string()));
+            },
+            other => panic!("Failed to parse {:?}", other)
+        }
+    }
+
+    #[test]
+    fn offset_fetch_of_all_the_topics() {
+        match kafka_request(&request(9, 2)[..]) {
+            IResult::Done(_, KafkaRequest { req: ApiRequest::FetchOffsets { group_id, topics }, .. }) => {
+                assert_eq!(group_id, "g");
+                assert!(topics.is_none());
+            },
+            other => panic!("Failed to parse {:?}", other)
+        }
+        match kafka_request(&request(9, 1)[..]) {
+            IResult::Done(_, KafkaRequest { req: ApiRequest::FetchOffsets { topics: Some(topics), .. }, .. }) => {
+                assert_eq!(topics[0].name, "t");
+                assert_eq!(topics[0].partitions, vec![0]);
+            },
+            other => panic!("Failed to parse {:?}", other)
+        }
+    }
+
+    #[test]
+    fn consecutive_versions_are_merged() {
+        let supported = supported_versions();
+        assert!(supported.contains(&(2, 0, 1)));
+        assert!(supported.contains(&(11, 0, 1)));
+        assert_eq!(supported.iter().filter(|s| s.0 == 2).count(), 1);
+    }
+
+    // Produce v3 request (without the size header) the way the Java producer sends it with no idempotence:
+    // a record batch of two records, the first one with a header
+    const JAVA_BATCH_PRODUCE: &'static [u8] = &[
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x07, 0x00, 0x0a, 0x70, 0x72,
+        0x6f, 0x64, 0x75, 0x63, 0x65, 0x72, 0x2d, 0x31, 0xff, 0xff, 0x00, 0x01,
+        0x00, 0x00, 0x75, 0x30, 0x00, 0x00, 0x00, 0x01, 0x00, 0x04, 0x74, 0x65,
+        0x73, 0x74, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x58, 0xff, 0xff, 0xff, 0xff, 0x02, 0xfa, 0xd4, 0xfc, 0xd3, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x5f, 0x27, 0xa1, 0x40,
+        0x01, 0x00, 0x00, 0x01, 0x5f, 0x27, 0xa1, 0x40, 0x06, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+        0x00, 0x00, 0x02, 0x28, 0x00, 0x00, 0x00, 0x04, 0x6b, 0x31, 0x04, 0x76,
+        0x31, 0x02, 0x0a, 0x74, 0x72, 0x61, 0x63, 0x65, 0x06, 0x61, 0x62, 0x63,
+        0x22, 0x00, 0x0a, 0x02, 0x01, 0x16, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20,
+        0x62, 0x61, 0x74, 0x63, 0x68, 0x00,
+    ];
+    const JAVA_BATCH: usize = 50; // where the record batch starts
+
+    fn produced(request: &[u8]) -> Option<Vec<KafkaMessage>> {
+        match kafka_request(request) {
+            IResult::Done(_, KafkaRequest { req: ApiRequest::Publish { mut topics, .. }, .. }) => topics.remove(0).messages.remove(0).1,
+            other => panic!("Failed to parse {:?}", other)
+        }
+    }
+
+    // Puts the crc of an edited batch right again
+    fn reseal(request: &mut Vec<u8>) {
+        let crc = crc32::checksum_castagnoli(&request[JAVA_BATCH + 21..]);
+        request[JAVA_BATCH + 17..JAVA_BATCH + 21].copy_from_slice(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+    }
+
+    #[test]
+    fn java_record_batch_is_decoded() {
+        let messages = produced(JAVA_BATCH_PRODUCE).expect("Corrupt batch");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].timestamp, 0x15f27a14001);
+        assert_eq!(messages[0].key, Some(b"k1".to_vec()));
+        assert_eq!(messages[0].value, Some(b"v1".to_vec()));
+        assert_eq!(messages[0].headers, vec![("trace".to_string(), Some(b"abc".to_vec()))]);
+        assert_eq!(messages[1].timestamp, 0x15f27a14006);
+        assert_eq!(messages[1].key, None);
+        assert_eq!(messages[1].value, Some(b"hello batch".to_vec()));
+        assert!(messages[1].headers.is_empty());
+    }
+
+    #[test]
+    fn corrupt_record_batches_reject_the_partition() {
+        let mut request = JAVA_BATCH_PRODUCE.to_vec();
+        let last = request.len() - 2;
+        request[last] ^= 0x20; // crc mismatch
+        assert!(produced(&request).is_none());
+
+        let mut request = JAVA_BATCH_PRODUCE.to_vec();
+        request[JAVA_BATCH + 22] = 0x07; // no such codec
+        reseal(&mut request);
+        assert!(produced(&request).is_none());
+
+        let mut request = JAVA_BATCH_PRODUCE.to_vec();
+        request[JAVA_BATCH + 60] = 3; // one record more than there is
+        reseal(&mut request);
+        assert!(produced(&request).is_none());
+
+        let mut request = JAVA_BATCH_PRODUCE.to_vec();
+        request[JAVA_BATCH + 60] = 1; // one record less than there is
+        reseal(&mut request);
+        assert!(produced(&request).is_none());
+    }
+
+    #[test]
+    fn varint_edge_cases() {
+        let cases: Vec<(i64, &[u8])> = vec![
+            (0, &[0x00]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (::std::i32::MAX as i64, &[0xfe, 0xff, 0xff, 0xff, 0x0f]),
+            (::std::i32::MIN as i64, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+            (::std::i64::MAX, &[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            (::std::i64::MIN, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01])
+        ];
+        for (value, encoded) in cases {
+            assert_eq!(varint(encoded), IResult::Done(&[][..], value));
+        }
+        assert!(varint(&[0xff, 0xff]).is_incomplete());
+        assert!(varint(&[0xff; 11]).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use config::{ConfigError, Config, File};
 use hostname::get_hostname;
+use compression::Compression;
 
 #[derive(Debug, Deserialize)]
 pub struct Database {
@@ -10,16 +11,122 @@ pub struct Database {
 pub struct Topic {
     pub name: String,
     pub compacted: Option<bool>,
-    pub retention: Option<u64>
+    pub retention: Option<u64>,
+    pub partitions: Option<u32>,
+    pub compression: Option<String>,
+    #[serde(rename = "message.timestamp.type")]
+    pub timestamp_type: Option<String>
+}
+
+impl Topic {
+    pub fn partitions(&self) -> u32 {
+        self.partitions.unwrap_or(1)
+    }
+
+    // CreateTime (default) keeps the producer's timestamps, LogAppendTime replaces them with the time of the write
+    pub fn log_append_time(&self) -> bool {
+        self.timestamp_type.as_ref().map(|t| t == "LogAppendTime").unwrap_or(false)
+    }
+
+    // The codec the fetched message sets are compressed with
+    pub fn compression(&self) -> Compression {
+        self.compression.as_ref().and_then(|c| Compression::from_name(c)).unwrap_or(Compression::None)
+    }
+}
+
+// The TLS of the SSL and SASL_SSL listeners, the files are PEM
+#[derive(Debug, Deserialize)]
+pub struct Ssl {
+    pub certificate: String, // the server certificate chain
+    pub key: String,
+    pub ca: Option<String>, // the client certificates are verified against it
+    pub client_auth: Option<String> // required, requested or none (default)
+}
+
+impl Ssl {
+    pub fn client_auth(&self) -> ClientAuth {
+        match self.client_auth.as_ref().map(|s| s.as_str()) {
+            Some("required") => ClientAuth::Required,
+            Some("requested") => ClientAuth::Requested,
+            _ => ClientAuth::None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    None,
+    Requested,
+    Required
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl
+}
+
+impl SecurityProtocol {
+    pub fn from_name(name: &str) -> Option<SecurityProtocol> {
+        match name {
+            "PLAINTEXT" => Some(SecurityProtocol::Plaintext),
+            "SSL" => Some(SecurityProtocol::Ssl),
+            "SASL_PLAINTEXT" => Some(SecurityProtocol::SaslPlaintext),
+            "SASL_SSL" => Some(SecurityProtocol::SaslSsl),
+            _ => None
+        }
+    }
+
+    pub fn tls(&self) -> bool {
+        *self == SecurityProtocol::Ssl || *self == SecurityProtocol::SaslSsl
+    }
+
+    pub fn sasl(&self) -> bool {
+        *self == SecurityProtocol::SaslPlaintext || *self == SecurityProtocol::SaslSsl
+    }
+}
+
+// Where the clients connect and the endpoint they are told to use afterwards
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub name: String,
+    pub protocol: SecurityProtocol,
+    pub bind: String, // host:port
+    pub advertised_host: String,
+    pub advertised_port: u32
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     listen: Option<String>,
+    listeners: Option<String>,
+    #[serde(rename = "advertised.listeners")]
+    advertised_listeners: Option<String>,
+    #[serde(rename = "listener.security.protocol.map")]
+    listener_security_protocol_map: Option<String>,
     hostname: Option<String>,
     pub cleanup: Option<u64>,
+    #[serde(rename = "offsets.retention.ms")]
+    pub offsets_retention: Option<u64>,
+    #[serde(rename = "delete.topic.enable")]
+    pub delete_topic_enable: Option<bool>,
+    #[serde(rename = "sasl.users")]
+    pub sasl_users: Option<String>,
+    #[serde(rename = "sasl.enabled.mechanisms")]
+    pub sasl_enabled_mechanisms: Option<Vec<String>>,
+    #[serde(rename = "authorizer.enable")]
+    pub authorizer_enable: Option<bool>,
+    #[serde(rename = "super.users")]
+    super_users: Option<String>,
+    #[serde(rename = "allow.everyone.if.no.acl.found")]
+    pub allow_everyone_if_no_acl_found: Option<bool>,
+    #[serde(rename = "message.max.bytes")]
+    pub message_max_bytes: Option<usize>,
     pub threads: Option<usize>,
     pub database: Database,
+    pub ssl: Option<Ssl>,
     pub topics: Vec<Topic>
 }
 
@@ -36,7 +143,23 @@ impl Settings {
 			None => String::from("0.0.0.0:9092")
 		}
 	}
+
+    // Without "listeners" there is the one at "listen", it asks for SASL when there are users
+    pub fn listeners(&self) -> Vec<Listener> {
+        let sasl = self.sasl_users.is_some() || self.sasl_enabled_mechanisms.as_ref().map(|m| !m.is_empty()).unwrap_or(false);
+        let default = format!("{}://{}", if sasl { "SASL_PLAINTEXT" } else { "PLAINTEXT" }, self.listen());
+        parse_listeners(self.listeners.as_ref().unwrap_or(&default),
+                        self.advertised_listeners.as_ref().map(|s| s.as_str()),
+                        self.listener_security_protocol_map.as_ref().map(|s| s.as_str()),
+                        &self.get_hostname())
+    }
 	
+	// "User:admin;User:CN=ops,O=example", separated by ';' since the certificate principals have commas
+	pub fn super_users(&self) -> Vec<String> {
+		self.super_users.as_ref().map(|s| s.split(';').map(|u| u.trim().to_string()).filter(|u| !u.is_empty()).collect())
+			.unwrap_or_default()
+	}
+
 	pub fn get_hostname(&self) -> String {
 		match self.hostname {
 			Some(ref v) => v.to_string(),
@@ -44,3 +167,69 @@ impl Settings {
 		}
 	}
 }
+
+// "NAME://host:port", the host is empty to listen on all the interfaces
+fn endpoint(s: &str) -> (String, String, u32) {
+    let mut parts = s.trim().splitn(2, "://");
+    let name = parts.next().unwrap_or("").to_string();
+    let address = parts.next().unwrap_or_else(|| panic!("Please check the listener {}, it should look like PLAINTEXT://host:9092", s));
+    let mut parts = address.rsplitn(2, ':');
+    let port = parts.next().and_then(|p| p.parse().ok()).unwrap_or_else(|| panic!("Please check the port number of the listener {}", s));
+    let host = parts.next().unwrap_or("").trim_matches(|c| c == '[' || c == ']').to_string();
+    (name, host, port)
+}
+
+fn parse_listeners(listeners: &str, advertised: Option<&str>, protocols: Option<&str>, hostname: &str) -> Vec<Listener> {
+    let protocols: Vec<(&str, SecurityProtocol)> = protocols.unwrap_or("").split(',').filter(|p| !p.trim().is_empty()).map(|p| {
+        let mut parts = p.trim().splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let protocol = parts.next().and_then(SecurityProtocol::from_name)
+            .unwrap_or_else(|| panic!("Please check the listener.security.protocol.map entry {}", p));
+        (name, protocol)
+    }).collect();
+    let advertised: Vec<(String, String, u32)> = advertised.unwrap_or("").split(',').filter(|a| !a.trim().is_empty()).map(endpoint).collect();
+    listeners.split(',').map(|l| {
+        let (name, host, port) = endpoint(l);
+        // The names of the listeners default to their protocols
+        let protocol = protocols.iter().find(|p| p.0 == name).map(|p| p.1).or_else(|| SecurityProtocol::from_name(&name))
+            .unwrap_or_else(|| panic!("Please add the listener {} to listener.security.protocol.map", name));
+        let (advertised_host, advertised_port) = match advertised.iter().find(|a| a.0 == name) {
+            Some(&(_, ref host, port)) => (host.to_string(), port),
+            None if host.is_empty() || host == "0.0.0.0" || host == "::" => (hostname.to_string(), port),
+            None => (host.to_string(), port)
+        };
+        let bind = if host.is_empty() {
+            format!("0.0.0.0:{}", port)
+        } else if host.contains(':') {
+            format!("[{}]:{}", host, port) // IPv6
+        } else {
+            format!("{}:{}", host, port)
+        };
+        Listener {
+            bind: bind,
+            name: name,
+            protocol: protocol,
+            advertised_host: advertised_host,
+            advertised_port: advertised_port
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listeners_advertise_their_own_endpoints() {
+        let listeners = parse_listeners("INTERNAL://:9092, EXTERNAL://10.0.0.5:9093,SSL://[::1]:9094",
+                                        Some("EXTERNAL://kafka.example.com:19093"),
+                                        Some("INTERNAL:PLAINTEXT,EXTERNAL:SASL_SSL"), "node1");
+        assert_eq!(listeners.len(), 3);
+        assert_eq!((listeners[0].protocol, listeners[0].bind.as_str()), (SecurityProtocol::Plaintext, "0.0.0.0:9092"));
+        assert_eq!((listeners[0].advertised_host.as_str(), listeners[0].advertised_port), ("node1", 9092));
+        assert_eq!((listeners[1].protocol, listeners[1].bind.as_str()), (SecurityProtocol::SaslSsl, "10.0.0.5:9093"));
+        assert_eq!((listeners[1].advertised_host.as_str(), listeners[1].advertised_port), ("kafka.example.com", 19093));
+        assert_eq!((listeners[2].protocol, listeners[2].bind.as_str()), (SecurityProtocol::Ssl, "[::1]:9094"));
+        assert_eq!(listeners[2].advertised_host, "::1");
+    }
+}
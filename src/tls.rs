@@ -0,0 +1,72 @@
+// TLS of the connections. The verified client certificates name the principal of the connection.
+
+use openssl::ssl::{SslAcceptor, SslMethod, SslFiletype, SslVerifyMode, SslStream};
+use openssl::x509::{X509Name, X509NameRef};
+use settings::{Ssl, ClientAuth};
+use auth;
+
+pub fn acceptor(cnf: &Ssl) -> SslAcceptor {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).expect("Failed to initialize TLS");
+    builder.set_certificate_chain_file(&cnf.certificate).expect("Failed to read the TLS certificate");
+    builder.set_private_key_file(&cnf.key, SslFiletype::PEM).expect("Failed to read the TLS key");
+    builder.check_private_key().expect("The TLS key does not match the certificate");
+    let client_auth = cnf.client_auth();
+    if client_auth != ClientAuth::None {
+        let ca = cnf.ca.as_ref().expect("Please set the ca file to verify the client certificates against");
+        builder.set_ca_file(ca).expect("Failed to read the TLS CA file");
+        builder.set_client_ca_list(X509Name::load_client_ca_file(ca).expect("Failed to read the TLS CA file"));
+        // A certificate that fails the verification fails the handshake in both modes
+        builder.set_verify(match client_auth {
+            ClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            _ => SslVerifyMode::PEER
+        });
+    }
+    builder.build()
+}
+
+// The subject of the client certificate the way Kafka names it, "User:CN=client,O=org"
+pub fn principal<S>(stream: &SslStream<S>) -> Option<String> {
+    stream.ssl().peer_certificate().map(|cert| auth::principal(&distinguished_name(cert.subject_name())))
+}
+
+// RFC 2253 form, the most specific attribute first
+fn distinguished_name(name: &X509NameRef) -> String {
+    let mut attributes: Vec<String> = name.entries().map(|e| {
+        let key = e.object().nid().short_name().map(|n| n.to_string()).unwrap_or_else(|_| e.object().to_string());
+        let value = e.data().as_utf8().map(|v| v.to_string()).unwrap_or_default();
+        format!("{}={}", key, escape(&value))
+    }).collect();
+    attributes.reverse();
+    attributes.join(",")
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => true,
+            '#' | ' ' => i == 0,
+            _ => false
+        };
+        if special || (c == ' ' && i == value.chars().count() - 1) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509NameBuilder;
+
+    #[test]
+    fn distinguished_name_starts_with_the_common_name() {
+        let mut builder = X509NameBuilder::new().unwrap();
+        builder.append_entry_by_text("C", "CA").unwrap();
+        builder.append_entry_by_text("O", "Uncle K").unwrap();
+        builder.append_entry_by_text("CN", "device, 42").unwrap();
+        assert_eq!(distinguished_name(&builder.build()), "CN=device\\, 42,O=Uncle K,C=CA");
+    }
+}
@@ -1,44 +1,183 @@
 use bytes::{BytesMut, BufMut, BigEndian};
 use crc::crc32;
-use parser::TopicWithPartitions; // TODO move to common place
+use parser;
+use parser::AclBinding;
+use compression;
+use compression::Compression;
+
+// Kafka error codes we return
+pub const UNKNOWN_SERVER_ERROR: u16 = 0xffff; // -1
+pub const NONE: u16 = 0;
+pub const OFFSET_OUT_OF_RANGE: u16 = 1;
+pub const CORRUPT_MESSAGE: u16 = 2;
+pub const UNKNOWN_TOPIC_OR_PARTITION: u16 = 3;
+pub const REQUEST_TIMED_OUT: u16 = 7;
+pub const INVALID_TOPIC_EXCEPTION: u16 = 17;
+pub const ILLEGAL_GENERATION: u16 = 22;
+pub const INCONSISTENT_GROUP_PROTOCOL: u16 = 23;
+pub const UNKNOWN_MEMBER_ID: u16 = 25;
+pub const REBALANCE_IN_PROGRESS: u16 = 27;
+pub const TOPIC_AUTHORIZATION_FAILED: u16 = 29;
+pub const GROUP_AUTHORIZATION_FAILED: u16 = 30;
+pub const CLUSTER_AUTHORIZATION_FAILED: u16 = 31;
+pub const UNSUPPORTED_SASL_MECHANISM: u16 = 33;
+pub const ILLEGAL_SASL_STATE: u16 = 34;
+pub const UNSUPPORTED_VERSION: u16 = 35;
+pub const TOPIC_ALREADY_EXISTS: u16 = 36;
+pub const INVALID_PARTITIONS: u16 = 37;
+pub const INVALID_REPLICATION_FACTOR: u16 = 38;
+pub const INVALID_CONFIG: u16 = 40;
+pub const INVALID_REQUEST: u16 = 42;
+pub const POLICY_VIOLATION: u16 = 44;
+pub const SECURITY_DISABLED: u16 = 54;
+pub const KAFKA_STORAGE_ERROR: u16 = 56;
+pub const SASL_AUTHENTICATION_FAILED: u16 = 58;
+pub const TOPIC_DELETION_DISABLED: u16 = 73;
+pub const RESOURCE_NOT_FOUND: u16 = 91;
+pub const DUPLICATE_RESOURCE: u16 = 92;
+pub const UNACCEPTABLE_CREDENTIAL: u16 = 93;
 
 // Anything that is a Kafka response body.
 #[derive(Debug)]
 pub enum ApiResponse {
-    VersionsResponse,
+    VersionsResponse {
+        version: i16,
+        error_code: u16
+    },
     ErrorResponse,
+    NoResponse, // acks=0 producers do not read the responses
     MetadataResponse {
         version: i16,
         cluster: ClusterMetadata
     },
     PublishResponse {
         version: i16,
-        responses: Vec<(String, Vec<u32>)>
+        responses: Vec<(String, Vec<(u32, u16, i64, i64)>)>
     },
     FetchResponse {
         version: i16,
-        responses: Vec<(String, Vec<(u64, Option<Vec<u8>>, Vec<u8>)>)>
+        responses: Vec<(String, Vec<FetchPartition>)>
     },
     GroupCoordinatorResponse {
-        hostname: String
+        error_code: u16,
+        hostname: String,
+        port: u32
     },
     JoinGroupResponse {
-        protocol: Option<(String, Option<Vec<u8>>)>
+        error_code: u16,
+        generation_id: i32,
+        protocol: Option<String>,
+        leader_id: String,
+        member_id: String,
+        members: Vec<(String, Option<Vec<u8>>)>
     },
     SyncGroupResponse {
+        error_code: u16,
         assignment: Option<Vec<u8>>
     },
     FetchOffsetsResponse {
-        topics: Vec<(String, Vec<(u32, i64)>)>
+        version: i16,
+        error_code: u16,
+        topics: Vec<(String, Vec<(u32, i64, Option<String>, u16)>)> // partition, offset, metadata and error code
     },
     OffsetsResponse {
-        topics: Vec<(String, Vec<(u32, i64)>)>
+        version: i16,
+        topics: Vec<(String, Vec<(u32, u16, i64, i64)>)>
     },
     OffsetCommitResponse {
-        topics: Vec<TopicWithPartitions>
+        topics: Vec<(String, Vec<(u32, u16)>)> // the partitions with their error codes
+    },
+    HeartbeatResponse {
+        error_code: u16
+    },
+    LeaveGroupResponse {
+        error_code: u16
+    },
+    CreateTopicsResponse {
+        version: i16,
+        topics: Vec<(String, u16, Option<String>)> // name, error code and message
+    },
+    DeleteTopicsResponse {
+        version: i16,
+        topics: Vec<(String, u16)>
+    },
+    ListGroupsResponse {
+        version: i16,
+        groups: Vec<(String, String)> // group id and protocol type
+    },
+    DescribeGroupsResponse {
+        version: i16,
+        groups: Vec<GroupDescription>
+    },
+    SaslHandshakeResponse {
+        error_code: u16,
+        mechanisms: Vec<String> // the enabled ones
+    },
+    SaslAuthenticateResponse {
+        version: i16,
+        error_code: u16,
+        error_message: Option<String>,
+        auth_bytes: Vec<u8>
+    },
+    DescribeScramCredentialsResponse {
+        error_code: u16,
+        results: Vec<(String, u16, Option<String>, Vec<(i8, i32)>)> // user, error, message and the mechanisms with their iterations
+    },
+    AlterScramCredentialsResponse {
+        results: Vec<(String, u16, Option<String>)>
+    },
+    DescribeAclsResponse {
+        version: i16,
+        error_code: u16,
+        error_message: Option<String>,
+        acls: Vec<AclBinding>
+    },
+    CreateAclsResponse {
+        results: Vec<(u16, Option<String>)> // one for every creation
+    },
+    DeleteAclsResponse {
+        version: i16,
+        results: Vec<(u16, Option<String>, Vec<AclBinding>)> // one for every filter, with the ACLs it deleted
     },
-    HeartbeatResponse,
-    LeaveGroupResponse,
+}
+
+#[derive(Debug)]
+pub struct GroupDescription {
+    pub error_code: u16,
+    pub group_id: String,
+    pub state: String,
+    pub protocol_type: String,
+    pub protocol: String,
+    pub members: Vec<MemberDescription>
+}
+
+#[derive(Debug)]
+pub struct MemberDescription {
+    pub member_id: String,
+    pub client_id: String,
+    pub client_host: String,
+    pub metadata: Option<Vec<u8>>,
+    pub assignment: Option<Vec<u8>>
+}
+
+#[derive(Debug)]
+pub struct FetchPartition {
+    pub partition: u32,
+    pub error_code: u16,
+    pub compression: Compression,
+    pub log_append_time: bool,
+    pub high_watermark: i64,
+    pub log_start_offset: i64,
+    pub records: Vec<Record>
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub offset: u64,
+    pub timestamp: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+    pub headers: Vec<(String, Option<Vec<u8>>)>
 }
 
 #[derive(Debug)]
@@ -61,34 +200,74 @@ impl KafkaResponseHeader {
     }
 }
 
-impl KafkaResponse {
-    pub fn is_empty(&self) -> bool {
-        match self.req {
-            ApiResponse::FetchResponse { ref responses, .. } => {
-                // responses: Vec<(String, Vec<(u64, Option<Vec<u8>>, Vec<u8>)>)>
-                !responses.iter().any(|t| t.1.iter().any(|r| !r.2.is_empty()))
-            },
-            _ => false
+impl Record {
+    // What the record takes on the wire in the format of the fetch version, before the compression.
+    // In a record batch (v4 and up) it depends on the first record of the batch.
+    pub fn size(&self, version: i16, first: &Record) -> usize {
+        if version < 4 {
+            return MESSAGE_OVERHEAD + opt_size(&self.key) + opt_size(&self.value); // no headers
         }
+        let bytes = |v: &Option<Vec<u8>>| varint_size(v.as_ref().map(|v| v.len() as i64).unwrap_or(-1)) + opt_size(v);
+        let body = 1 // attributes
+            + varint_size(self.timestamp - first.timestamp)
+            + varint_size((self.offset - first.offset) as i64)
+            + bytes(&self.key)
+            + bytes(&self.value)
+            + varint_size(self.headers.len() as i64)
+            + self.headers.iter().map(|h| varint_size(h.0.len() as i64) + h.0.len() + bytes(&h.1)).sum::<usize>();
+        varint_size(body as i64) + body
     }
 }
 
+// What the records of a partition take on top of their own sizes
+pub fn records_overhead(version: i16, codec: Compression) -> usize {
+    match (version >= 4, codec) {
+        (true, _)                  => BATCH_OVERHEAD,
+        (false, Compression::None) => 0,
+        (false, _)                 => MESSAGE_OVERHEAD // the wrapper message
+    }
+}
+
+// The exact size of the records of a partition in the fetch response, compressed and all
+pub fn records_size(version: i16, p: &FetchPartition) -> usize {
+    let mut buf = BytesMut::with_capacity(1024);
+    partition_records_to_bytes(version, p, &mut buf);
+    buf.len()
+}
+
 pub fn to_bytes(msg: &KafkaResponse, out: &mut BytesMut) {
+    if let ApiResponse::NoResponse = msg.req {
+        return; // Not even the size header
+    }
     let mut buf = BytesMut::with_capacity(1024);
     match msg.req {
-        ApiResponse::VersionsResponse => versions_to_bytes(&mut buf),
-        ApiResponse::GroupCoordinatorResponse {ref hostname} => coordinator_to_bytes(hostname, &mut buf),
-        ApiResponse::JoinGroupResponse {ref protocol} => join_group_to_bytes(protocol, &mut buf),
+        ApiResponse::VersionsResponse { version, error_code } => versions_to_bytes(version, error_code, &mut buf),
+        ApiResponse::GroupCoordinatorResponse {error_code, ref hostname, port} => coordinator_to_bytes(error_code, hostname, port, &mut buf),
+        ApiResponse::JoinGroupResponse {error_code, generation_id, ref protocol, ref leader_id, ref member_id, ref members} =>
+            join_group_to_bytes(error_code, generation_id, protocol, leader_id, member_id, members, &mut buf),
         ApiResponse::MetadataResponse { version: 2, ref cluster } => metadata_to_bytes(cluster, &mut buf),
-        ApiResponse::PublishResponse { version: 2, ref responses } => publish_to_bytes(responses, &mut buf),
-        ApiResponse::FetchResponse { version: 2, ref responses } => fetch_to_bytes(responses, &mut buf),
-        ApiResponse::FetchResponse { version: 3, ref responses } => fetch_to_bytes(responses, &mut buf),
-        ApiResponse::SyncGroupResponse { ref assignment } => sync_group_to_bytes(assignment, &mut buf),
-        ApiResponse::FetchOffsetsResponse { ref topics } => fetch_offsets_to_bytes(topics, &mut buf),
-        ApiResponse::OffsetsResponse { ref topics } => offsets_to_bytes(topics, &mut buf),
+        ApiResponse::PublishResponse { version, ref responses } => publish_to_bytes(version, responses, &mut buf),
+        ApiResponse::FetchResponse { version, ref responses } => fetch_to_bytes(version, responses, &mut buf),
+        ApiResponse::SyncGroupResponse { error_code, ref assignment } => sync_group_to_bytes(error_code, assignment, &mut buf),
+        ApiResponse::FetchOffsetsResponse { version, error_code, ref topics } => fetch_offsets_to_bytes(version, error_code, topics, &mut buf),
+        ApiResponse::OffsetsResponse { version, ref topics } => offsets_to_bytes(version, topics, &mut buf),
         ApiResponse::OffsetCommitResponse { ref topics } => offset_commit_to_bytes(topics, &mut buf),
-        ApiResponse::HeartbeatResponse => heartbeat_to_bytes(&mut buf),
-        ApiResponse::LeaveGroupResponse => heartbeat_to_bytes(&mut buf), // version 0 we support now is the same response as heartbeat
+        ApiResponse::HeartbeatResponse { error_code } => heartbeat_to_bytes(error_code, &mut buf),
+        ApiResponse::LeaveGroupResponse { error_code } => heartbeat_to_bytes(error_code, &mut buf), // version 0 we support now is the same response as heartbeat
+        ApiResponse::CreateTopicsResponse { version, ref topics } => create_topics_to_bytes(version, topics, &mut buf),
+        ApiResponse::DeleteTopicsResponse { version, ref topics } => delete_topics_to_bytes(version, topics, &mut buf),
+        ApiResponse::ListGroupsResponse { version, ref groups } => list_groups_to_bytes(version, groups, &mut buf),
+        ApiResponse::DescribeGroupsResponse { version, ref groups } => describe_groups_to_bytes(version, groups, &mut buf),
+        ApiResponse::SaslHandshakeResponse { error_code, ref mechanisms } => sasl_handshake_to_bytes(error_code, mechanisms, &mut buf),
+        ApiResponse::SaslAuthenticateResponse { version, error_code, ref error_message, ref auth_bytes } =>
+            sasl_authenticate_to_bytes(version, error_code, error_message, auth_bytes, &mut buf),
+        ApiResponse::DescribeScramCredentialsResponse { error_code, ref results } =>
+            describe_scram_credentials_to_bytes(error_code, results, &mut buf),
+        ApiResponse::AlterScramCredentialsResponse { ref results } => alter_scram_credentials_to_bytes(results, &mut buf),
+        ApiResponse::DescribeAclsResponse { version, error_code, ref error_message, ref acls } =>
+            describe_acls_to_bytes(version, error_code, error_message, acls, &mut buf),
+        ApiResponse::CreateAclsResponse { ref results } => create_acls_to_bytes(results, &mut buf),
+        ApiResponse::DeleteAclsResponse { version, ref results } => delete_acls_to_bytes(version, results, &mut buf),
         _ => error_to_bytes(&mut buf)
     }
     out.reserve(8);
@@ -98,35 +277,42 @@ pub fn to_bytes(msg: &KafkaResponse, out: &mut BytesMut) {
 }
 
 
-fn versions_to_bytes(out: &mut BytesMut) {
-    out.put_u16::<BigEndian>(0); // error_code
-    out.put_u32::<BigEndian>(21); // number of api calls supported
-    versions_supported_call(out, 0, 0, 2);
-    versions_supported_call(out, 1, 0, 3);
-    versions_supported_call(out, 2, 0, 1);
-    versions_supported_call(out, 3, 0, 2);
-    versions_supported_call(out, 4, 0, 0);
-    versions_supported_call(out, 5, 0, 0);
-    versions_supported_call(out, 6, 0, 3);
-    versions_supported_call(out, 7, 1, 1);
-    versions_supported_call(out, 8, 0, 2);
-    versions_supported_call(out, 9, 0, 2);
-    versions_supported_call(out, 10, 0, 0);
-    versions_supported_call(out, 11, 0, 1);
-    versions_supported_call(out, 12, 0, 0);
-    versions_supported_call(out, 13, 0, 0);
-    versions_supported_call(out, 14, 0, 0);
-    versions_supported_call(out, 15, 0, 0);
-    versions_supported_call(out, 16, 0, 0);
-    versions_supported_call(out, 17, 0, 0);
-    versions_supported_call(out, 18, 0, 0);
-    versions_supported_call(out, 19, 0, 1);
-    versions_supported_call(out, 20, 0, 0);
-}
-fn versions_supported_call(out: &mut BytesMut, opcode: u16, min: u16, max: u16) {
-    out.put_u16::<BigEndian>(opcode);
-    out.put_u16::<BigEndian>(min);
-    out.put_u16::<BigEndian>(max);
+// The response header stays v0 even for the flexible v3, the client cannot know our header versions yet
+fn versions_to_bytes(version: i16, error_code: u16, out: &mut BytesMut) {
+    let supported = parser::supported_versions();
+    out.reserve(40 + 7 * supported.len());
+    out.put_u16::<BigEndian>(error_code);
+    if version >= 3 {
+        unsigned_varint_to_bytes(supported.len() as u64 + 1, out); // compact array
+    } else {
+        out.put_u32::<BigEndian>(supported.len() as u32); // number of api calls supported
+    }
+    for (opcode, min, max) in supported {
+        versions_supported_call(out, opcode, min, max);
+        if version >= 3 {
+            out.put_u8(0); // tagged fields
+        }
+    }
+    if version >= 1 {
+        out.put_u32::<BigEndian>(0); // throttle_time
+    }
+    if version >= 3 {
+        out.put_u8(3); // tagged fields
+        out.put_u8(0); // supported features
+        out.put_u8(1);
+        out.put_u8(1); // empty compact array
+        out.put_u8(1); // finalized features epoch
+        out.put_u8(8);
+        out.put_i64::<BigEndian>(-1);
+        out.put_u8(2); // finalized features
+        out.put_u8(1);
+        out.put_u8(1); // empty compact array
+    }
+}
+fn versions_supported_call(out: &mut BytesMut, opcode: i16, min: i16, max: i16) {
+    out.put_i16::<BigEndian>(opcode);
+    out.put_i16::<BigEndian>(min);
+    out.put_i16::<BigEndian>(max);
 }
 
 fn error_to_bytes(out: &mut BytesMut) {
@@ -148,6 +334,221 @@ fn opt_string_to_bytes(msg: &Option<String>, out: &mut BytesMut) {
     }
 }
 
+fn create_topics_to_bytes(version: i16, topics: &Vec<(String, u16, Option<String>)>, out: &mut BytesMut) {
+    out.reserve(8);
+    if version >= 2 {
+        out.put_u32::<BigEndian>(0); // throttle_time
+    }
+    out.put_u32::<BigEndian>(topics.len() as u32);
+    for topic in topics {
+        out.reserve(8 + topic.0.len() + topic.2.as_ref().map(|m| m.len()).unwrap_or(0));
+        string_to_bytes(&topic.0, out);
+        out.put_u16::<BigEndian>(topic.1); // error_code
+        if version >= 1 {
+            opt_string_to_bytes(&topic.2, out); // error_message
+        }
+    }
+}
+
+fn delete_topics_to_bytes(version: i16, topics: &Vec<(String, u16)>, out: &mut BytesMut) {
+    out.reserve(8);
+    if version >= 1 {
+        out.put_u32::<BigEndian>(0); // throttle_time
+    }
+    out.put_u32::<BigEndian>(topics.len() as u32);
+    for topic in topics {
+        out.reserve(4 + topic.0.len());
+        string_to_bytes(&topic.0, out);
+        out.put_u16::<BigEndian>(topic.1); // error_code
+    }
+}
+
+fn list_groups_to_bytes(version: i16, groups: &Vec<(String, String)>, out: &mut BytesMut) {
+    out.reserve(10);
+    if version >= 1 {
+        out.put_u32::<BigEndian>(0); // throttle_time
+    }
+    out.put_u16::<BigEndian>(0); // error_code
+    out.put_u32::<BigEndian>(groups.len() as u32);
+    for group in groups {
+        out.reserve(4 + group.0.len() + group.1.len());
+        string_to_bytes(&group.0, out);
+        string_to_bytes(&group.1, out); // protocol type
+    }
+}
+
+fn describe_groups_to_bytes(version: i16, groups: &Vec<GroupDescription>, out: &mut BytesMut) {
+    out.reserve(8);
+    if version >= 1 {
+        out.put_u32::<BigEndian>(0); // throttle_time
+    }
+    out.put_u32::<BigEndian>(groups.len() as u32);
+    for group in groups {
+        out.reserve(16 + group.group_id.len() + group.state.len() + group.protocol_type.len() + group.protocol.len());
+        out.put_u16::<BigEndian>(group.error_code);
+        string_to_bytes(&group.group_id, out);
+        string_to_bytes(&group.state, out);
+        string_to_bytes(&group.protocol_type, out);
+        string_to_bytes(&group.protocol, out);
+        out.put_u32::<BigEndian>(group.members.len() as u32);
+        for m in &group.members {
+            out.reserve(14 + m.member_id.len() + m.client_id.len() + m.client_host.len() + opt_size(&m.metadata) + opt_size(&m.assignment));
+            string_to_bytes(&m.member_id, out);
+            string_to_bytes(&m.client_id, out);
+            string_to_bytes(&m.client_host, out);
+            opt_vec_to_bytes(&m.metadata, out);
+            opt_vec_to_bytes(&m.assignment, out);
+        }
+    }
+}
+
+fn sasl_handshake_to_bytes(error_code: u16, mechanisms: &Vec<String>, out: &mut BytesMut) {
+    out.reserve(6 + mechanisms.iter().map(|m| 2 + m.len()).sum::<usize>());
+    out.put_u16::<BigEndian>(error_code);
+    out.put_u32::<BigEndian>(mechanisms.len() as u32);
+    for m in mechanisms {
+        string_to_bytes(m, out);
+    }
+}
+
+fn sasl_authenticate_to_bytes(version: i16, error_code: u16, error_message: &Option<String>, auth_bytes: &Vec<u8>, out: &mut BytesMut) {
+    out.reserve(16 + error_message.as_ref().map(|m| m.len()).unwrap_or(0) + auth_bytes.len());
+    out.put_u16::<BigEndian>(error_code);
+    opt_string_to_bytes(error_message, out);
+    out.put_u32::<BigEndian>(auth_bytes.len() as u32);
+    out.put_slice(auth_bytes);
+    if version >= 1 {
+        out.put_i64::<BigEndian>(0); // session_lifetime_ms, the sessions do not expire
+    }
+}
+
+// The compact strings of the flexible versions carry the length + 1, 0 is null
+fn compact_string_to_bytes(msg: &str, out: &mut BytesMut) {
+    unsigned_varint_to_bytes(msg.len() as u64 + 1, out);
+    out.put_slice(msg.as_bytes());
+}
+
+fn compact_opt_string_to_bytes(msg: &Option<String>, out: &mut BytesMut) {
+    match *msg {
+        Some(ref s) => compact_string_to_bytes(s, out),
+        None => out.put_u8(0)
+    }
+}
+
+fn opt_string_size(msg: &Option<String>) -> usize {
+    msg.as_ref().map(|s| s.len()).unwrap_or(0)
+}
+
+// Both UserScramCredentials responses only have flexible versions
+fn describe_scram_credentials_to_bytes(error_code: u16, results: &Vec<(String, u16, Option<String>, Vec<(i8, i32)>)>, out: &mut BytesMut) {
+    out.reserve(20);
+    out.put_u8(0); // response header tags
+    out.put_u32::<BigEndian>(0); // throttle_time
+    out.put_u16::<BigEndian>(error_code);
+    out.put_u8(0); // error message
+    unsigned_varint_to_bytes(results.len() as u64 + 1, out);
+    for &(ref user, error_code, ref error_message, ref credentials) in results {
+        out.reserve(20 + user.len() + opt_string_size(error_message) + 6 * credentials.len());
+        compact_string_to_bytes(user, out);
+        out.put_u16::<BigEndian>(error_code);
+        compact_opt_string_to_bytes(error_message, out);
+        unsigned_varint_to_bytes(credentials.len() as u64 + 1, out);
+        for &(mechanism, iterations) in credentials {
+            out.put_i8(mechanism);
+            out.put_i32::<BigEndian>(iterations);
+            out.put_u8(0); // tags
+        }
+        out.put_u8(0); // tags
+    }
+    out.reserve(1);
+    out.put_u8(0); // tags
+}
+
+fn alter_scram_credentials_to_bytes(results: &Vec<(String, u16, Option<String>)>, out: &mut BytesMut) {
+    out.reserve(15);
+    out.put_u8(0); // response header tags
+    out.put_u32::<BigEndian>(0); // throttle_time
+    unsigned_varint_to_bytes(results.len() as u64 + 1, out);
+    for &(ref user, error_code, ref error_message) in results {
+        out.reserve(13 + user.len() + opt_string_size(error_message));
+        compact_string_to_bytes(user, out);
+        out.put_u16::<BigEndian>(error_code);
+        compact_opt_string_to_bytes(error_message, out);
+        out.put_u8(0); // tags
+    }
+    out.reserve(1);
+    out.put_u8(0); // tags
+}
+
+// The resources of DescribeAcls group their ACLs, the ones of a resource are next to each other in the list
+fn describe_acls_to_bytes(version: i16, error_code: u16, error_message: &Option<String>, acls: &Vec<AclBinding>, out: &mut BytesMut) {
+    let mut resources: Vec<(i8, &str, i8, Vec<&AclBinding>)> = Vec::new();
+    for acl in acls {
+        match resources.iter().position(|r| r.0 == acl.resource_type && r.1 == acl.resource_name && r.2 == acl.pattern_type) {
+            Some(i) => resources[i].3.push(acl),
+            None => resources.push((acl.resource_type, &acl.resource_name, acl.pattern_type, vec![acl]))
+        }
+    }
+    out.reserve(14 + opt_string_size(error_message));
+    out.put_u32::<BigEndian>(0); // throttle_time
+    out.put_u16::<BigEndian>(error_code);
+    opt_string_to_bytes(error_message, out);
+    out.put_u32::<BigEndian>(resources.len() as u32);
+    for &(resource_type, name, pattern_type, ref acls) in &resources {
+        out.reserve(8 + name.len());
+        out.put_i8(resource_type);
+        string_to_bytes(name, out);
+        if version >= 1 {
+            out.put_i8(pattern_type);
+        }
+        out.put_u32::<BigEndian>(acls.len() as u32);
+        for acl in acls {
+            out.reserve(6 + acl.principal.len() + acl.host.len());
+            string_to_bytes(&acl.principal, out);
+            string_to_bytes(&acl.host, out);
+            out.put_i8(acl.operation);
+            out.put_i8(acl.permission_type);
+        }
+    }
+}
+
+fn create_acls_to_bytes(results: &Vec<(u16, Option<String>)>, out: &mut BytesMut) {
+    out.reserve(8);
+    out.put_u32::<BigEndian>(0); // throttle_time
+    out.put_u32::<BigEndian>(results.len() as u32);
+    for &(error_code, ref error_message) in results {
+        out.reserve(4 + opt_string_size(error_message));
+        out.put_u16::<BigEndian>(error_code);
+        opt_string_to_bytes(error_message, out);
+    }
+}
+
+fn delete_acls_to_bytes(version: i16, results: &Vec<(u16, Option<String>, Vec<AclBinding>)>, out: &mut BytesMut) {
+    out.reserve(8);
+    out.put_u32::<BigEndian>(0); // throttle_time
+    out.put_u32::<BigEndian>(results.len() as u32);
+    for &(error_code, ref error_message, ref acls) in results {
+        out.reserve(8 + opt_string_size(error_message));
+        out.put_u16::<BigEndian>(error_code);
+        opt_string_to_bytes(error_message, out);
+        out.put_u32::<BigEndian>(acls.len() as u32);
+        for acl in acls {
+            out.reserve(16 + acl.resource_name.len() + acl.principal.len() + acl.host.len());
+            out.put_u16::<BigEndian>(NONE);
+            out.put_i16::<BigEndian>(-1); // error message
+            out.put_i8(acl.resource_type);
+            string_to_bytes(&acl.resource_name, out);
+            if version >= 1 {
+                out.put_i8(acl.pattern_type);
+            }
+            string_to_bytes(&acl.principal, out);
+            string_to_bytes(&acl.host, out);
+            out.put_i8(acl.operation);
+            out.put_i8(acl.permission_type);
+        }
+    }
+}
+
 fn opt_vec_to_bytes(msg: &Option<Vec<u8>>, out: &mut BytesMut) {
     match *msg {
         None    => out.put_u32::<BigEndian>(0),
@@ -225,88 +626,103 @@ pub struct PartitionMetadata {
     isr: Vec<u32>
 }
 impl TopicMetadata {
-    fn healthy(name: &String) -> TopicMetadata {
+    fn healthy(name: &String, partitions: u32) -> TopicMetadata {
         TopicMetadata {
             error_code: 0,
             name: name.to_string(),
             is_internal: 0,
-            partitions: vec![PartitionMetadata {
+            partitions: (0..partitions).map(|id| PartitionMetadata {
                 error_code: 0,
-                id: 0,
+                id: id,
                 leader: 0,
                 replicas: vec![0],
                 isr: vec![0]
-            }]
+            }).collect()
+        }
+    }
+
+    fn failed(name: &String, error_code: u16) -> TopicMetadata {
+        TopicMetadata {
+            error_code: error_code,
+            name: name.to_string(),
+            is_internal: 0,
+            partitions: Vec::new()
         }
     }
 }
 
-fn publish_to_bytes(msg: &Vec<(String, Vec<u32>)>, out: &mut BytesMut) {
+// Partitions are (partition, error_code, base_offset, log_append_time)
+fn publish_to_bytes(version: i16, msg: &Vec<(String, Vec<(u32, u16, i64, i64)>)>, out: &mut BytesMut) {
     out.put_u32::<BigEndian>(msg.len() as u32);
     for topic in msg {
         string_to_bytes(&topic.0, out);
         out.put_u32::<BigEndian>(topic.1.len() as u32);
         for partition in &topic.1 {
-            out.put_u32::<BigEndian>(*partition);
-            out.put_u16::<BigEndian>(0); // error code
-            out.put_u64::<BigEndian>(0); // offset
-            out.put_u64::<BigEndian>(0); // log append time
+            out.put_u32::<BigEndian>(partition.0);
+            out.put_u16::<BigEndian>(partition.1); // error code
+            out.put_i64::<BigEndian>(partition.2); // base offset of the batch
+            if version >= 2 {
+                out.put_i64::<BigEndian>(partition.3); // log append time, -1 for CreateTime topics
+            }
+            if version >= 5 {
+                out.put_i64::<BigEndian>(-1); // log start offset
+            }
         }
     }
-    out.put_u32::<BigEndian>(0); // throttle_time
+    if version >= 1 {
+        out.put_u32::<BigEndian>(0); // throttle_time
+    }
 }
 
 
 impl ApiResponse {
-    pub fn metadata_healthy(version: i16, topics: &Vec<String>, hostname: &String) -> ApiResponse {
+    // The topics are either their partition count or the error to report for them
+    pub fn metadata(version: i16, topics: &Vec<(String, Result<u32, u16>)>, hostname: &str, port: u32) -> ApiResponse {
         ApiResponse::MetadataResponse {
             version: version,
             cluster: ClusterMetadata {
                 brokers: vec![BrokerMetadata{
                     node_id: 0,
                     host: hostname.to_string(),
-                    port: 9092, // TODO
+                    port: port,
                     rack: None
                 }],
                 cluster_id: "UncleK".to_string(),
                 controller_id: 0,
-                topics: topics.iter().map(TopicMetadata::healthy).collect()
+                topics: topics.iter().map(|&(ref name, partitions)| match partitions {
+                    Ok(count)       => TopicMetadata::healthy(name, count),
+                    Err(error_code) => TopicMetadata::failed(name, error_code)
+                }).collect()
             }
         }
     }
 }
 
-fn coordinator_to_bytes(hostname: &str, out: &mut BytesMut) {
-    out.put_u16::<BigEndian>(0); // error_code
+fn coordinator_to_bytes(error_code: u16, hostname: &str, port: u32, out: &mut BytesMut) {
+    out.put_u16::<BigEndian>(error_code);
     out.put_u32::<BigEndian>(0); // node_id
     string_to_bytes(hostname, out);
-    out.put_u32::<BigEndian>(9092); // port
+    out.put_u32::<BigEndian>(port);
     
 }
 
-fn join_group_to_bytes(protocol: &Option<(String, Option<Vec<u8>>)>, out: &mut BytesMut) {
-    out.put_u16::<BigEndian>(0); // error_code
-    out.put_u32::<BigEndian>(0); // generation_id
-    match *protocol {
-        Some((ref s, _)) => string_to_bytes(s, out),
-        None             => out.put_i16::<BigEndian>(-1)
-    }
-    string_to_bytes(&String::from(""), out);   // leader_id
-    string_to_bytes(&String::from(""), out);   // member_id
-    // members
-    match *protocol {
-        Some((ref s, ref a)) => {
-            out.put_u32::<BigEndian>(1);
-            string_to_bytes(s, out);   // member_id
-            opt_vec_to_bytes(a, out);  // metadata
-        },
-        None => out.put_u32::<BigEndian>(0)
+fn join_group_to_bytes(error_code: u16, generation_id: i32, protocol: &Option<String>, leader_id: &str, member_id: &str,
+                       members: &Vec<(String, Option<Vec<u8>>)>, out: &mut BytesMut) {
+    out.put_u16::<BigEndian>(error_code);
+    out.put_i32::<BigEndian>(generation_id);
+    opt_string_to_bytes(protocol, out);
+    string_to_bytes(leader_id, out);
+    string_to_bytes(member_id, out);
+    // members, only filled in for the leader
+    out.put_u32::<BigEndian>(members.len() as u32);
+    for &(ref id, ref metadata) in members {
+        string_to_bytes(id, out);         // member_id
+        opt_vec_to_bytes(metadata, out);  // metadata
     }
-
 }
 
-fn sync_group_to_bytes(assignment: &Option<Vec<u8>>, out: &mut BytesMut) {
-    out.put_u16::<BigEndian>(0); // error_code
+fn sync_group_to_bytes(error_code: u16, assignment: &Option<Vec<u8>>, out: &mut BytesMut) {
+    out.put_u16::<BigEndian>(error_code);
     match *assignment {
         None => out.put_u32::<BigEndian>(0),
         Some(ref a) => {
@@ -316,7 +732,7 @@ fn sync_group_to_bytes(assignment: &Option<Vec<u8>>, out: &mut BytesMut) {
     }
 }
 
-fn fetch_offsets_to_bytes(topics: &Vec<(String, Vec<(u32, i64)>)>, out: &mut BytesMut) {
+fn fetch_offsets_to_bytes(version: i16, error_code: u16, topics: &Vec<(String, Vec<(u32, i64, Option<String>, u16)>)>, out: &mut BytesMut) {
     out.put_u32::<BigEndian>(topics.len() as u32);
     for topic in topics {
         string_to_bytes(&topic.0, out);
@@ -324,41 +740,51 @@ fn fetch_offsets_to_bytes(topics: &Vec<(String, Vec<(u32, i64)>)>, out: &mut Byt
         for p in &topic.1 {
             out.put_u32::<BigEndian>(p.0); // partition
             out.put_i64::<BigEndian>(p.1); // offset
-            opt_string_to_bytes(&None, out);
-            out.put_u16::<BigEndian>(0); // error_code
+            opt_string_to_bytes(&p.2, out); // metadata
+            out.put_u16::<BigEndian>(p.3); // error_code
         }
     }
-    out.put_u16::<BigEndian>(0); // error_code
+    if version >= 2 {
+        out.put_u16::<BigEndian>(error_code);
+    }
 }
 
-fn offsets_to_bytes(topics: &Vec<(String, Vec<(u32, i64)>)>, out: &mut BytesMut) {
+// Partitions are (partition, error_code, timestamp, offset)
+fn offsets_to_bytes(version: i16, topics: &Vec<(String, Vec<(u32, u16, i64, i64)>)>, out: &mut BytesMut) {
     out.put_u32::<BigEndian>(topics.len() as u32);
     for topic in topics {
         string_to_bytes(&topic.0, out);
         out.put_u32::<BigEndian>(topic.1.len() as u32);
         for p in &topic.1 {
             out.put_u32::<BigEndian>(p.0); // partition
-            out.put_u16::<BigEndian>(0); // error_code
-            out.put_u64::<BigEndian>(0); // timestamp
-            out.put_i64::<BigEndian>(p.1); // offset
+            out.put_u16::<BigEndian>(p.1); // error_code
+            if version >= 1 {
+                out.put_i64::<BigEndian>(p.2); // timestamp
+                out.put_i64::<BigEndian>(p.3); // offset
+            } else if p.3 < 0 {
+                out.put_u32::<BigEndian>(0); // no offsets found
+            } else {
+                out.put_u32::<BigEndian>(1);
+                out.put_i64::<BigEndian>(p.3); // offset
+            }
         }
     }
 }
 
-fn offset_commit_to_bytes(topics: &Vec<TopicWithPartitions>, out: &mut BytesMut) {
+fn offset_commit_to_bytes(topics: &Vec<(String, Vec<(u32, u16)>)>, out: &mut BytesMut) {
     out.put_u32::<BigEndian>(topics.len() as u32);
     for topic in topics {
-        string_to_bytes(&topic.name, out);
-        out.put_u32::<BigEndian>(topic.partitions.len() as u32);
-        for p in &topic.partitions {
-            out.put_u32::<BigEndian>(*p); // partition
-            out.put_u16::<BigEndian>(0); // error_code
+        string_to_bytes(&topic.0, out);
+        out.put_u32::<BigEndian>(topic.1.len() as u32);
+        for p in &topic.1 {
+            out.put_u32::<BigEndian>(p.0); // partition
+            out.put_u16::<BigEndian>(p.1); // error_code
         }
     }
 }
 
-fn heartbeat_to_bytes(out: &mut BytesMut) {
-    out.put_u16::<BigEndian>(0); // error_code
+fn heartbeat_to_bytes(error_code: u16, out: &mut BytesMut) {
+    out.put_u16::<BigEndian>(error_code);
 }
 
 fn opt_size(value: &Option<Vec<u8>>) -> usize {
@@ -368,35 +794,273 @@ fn opt_size(value: &Option<Vec<u8>>) -> usize {
     }
 }
 
-fn records_to_bytes(records: &Vec<(u64, Option<Vec<u8>>, Vec<u8>)>, out: &mut BytesMut) {
+const LOG_APPEND_TIME: u8 = 0x08; // timestamp type bit of the attributes
+const MESSAGE_OVERHEAD: usize = 34; // offset, size, crc, magic, attributes, timestamp and the key and value lengths of magic 1
+const BATCH_OVERHEAD: usize = 61; // everything of a record batch before the first record
+
+// Message set of magic 1 messages
+fn records_to_bytes(records: &Vec<Record>, log_append_time: bool, out: &mut BytesMut) {
     for r in records {
-        out.put_u64::<BigEndian>(r.0);
-        out.put_u32::<BigEndian>((22 + opt_size(&r.1) + r.2.len()) as u32);
-        let mut buf = BytesMut::with_capacity(18 + opt_size(&r.1) + r.2.len());
+        let size = 18 + opt_size(&r.key) + opt_size(&r.value);
+        out.reserve(16 + size);
+        out.put_u64::<BigEndian>(r.offset);
+        out.put_u32::<BigEndian>((4 + size) as u32);
+        let mut buf = BytesMut::with_capacity(size);
         buf.put_u8(0x01);  // magic
-        buf.put_u8(0x00);  // attributes
-        buf.put_u64::<BigEndian>(0); // timestamp
-        opt_vec_to_bytes(&r.1, &mut buf); // key
-        buf.put_u32::<BigEndian>(r.2.len() as u32); // value len
-        buf.put(&r.2); // value
+        buf.put_u8(if log_append_time { LOG_APPEND_TIME } else { 0x00 });  // attributes
+        buf.put_i64::<BigEndian>(r.timestamp); // timestamp
+        opt_vec_to_bytes(&r.key, &mut buf); // key
+        buf.put_u32::<BigEndian>(opt_size(&r.value) as u32); // value len
+        if let Some(ref value) = r.value {
+            buf.put(value); // value
+        }
         out.put_u32::<BigEndian>(crc32::checksum_ieee(&buf[..])); // crc32
         out.extend(buf.take());
     }
 }
 
-fn fetch_to_bytes(msg: &Vec<(String, Vec<(u64, Option<Vec<u8>>, Vec<u8>)>)>, out: &mut BytesMut) {
-    out.put_u32::<BigEndian>(0); // throttle 
+// All the records go into a single wrapper message. The inner offsets are relative to the first record
+// and the wrapper carries the offset of the last one.
+fn compressed_records_to_bytes(records: &Vec<Record>, codec: Compression, log_append_time: bool, out: &mut BytesMut) {
+    let (first, last) = match (records.first(), records.last()) {
+        (Some(f), Some(l)) => (f.offset, l.offset),
+        _ => return
+    };
+    let relative: Vec<Record> = records.iter().map(|r| Record { offset: r.offset - first, .. r.clone() }).collect();
+    let max_timestamp = records.iter().map(|r| r.timestamp).max().unwrap_or(-1);
+    let mut inner = BytesMut::with_capacity(1024);
+    records_to_bytes(&relative, false, &mut inner);
+    let value = compression::compress(codec, &inner[..]).expect("Failed to compress the records");
+    out.reserve(34 + value.len());
+    out.put_u64::<BigEndian>(last);
+    out.put_u32::<BigEndian>((22 + value.len()) as u32);
+    let mut buf = BytesMut::with_capacity(18 + value.len());
+    buf.put_u8(0x01);  // magic
+    buf.put_u8(codec.attributes() | if log_append_time { LOG_APPEND_TIME } else { 0x00 });  // attributes
+    buf.put_i64::<BigEndian>(max_timestamp); // timestamp
+    buf.put_i32::<BigEndian>(-1); // key
+    buf.put_u32::<BigEndian>(value.len() as u32); // value len
+    buf.put(&value); // value
+    out.put_u32::<BigEndian>(crc32::checksum_ieee(&buf[..])); // crc32
+    out.extend(buf.take());
+}
+
+// Zigzag encoded varint/varlong of the record batch v2
+fn varint_to_bytes(value: i64, out: &mut BytesMut) {
+    unsigned_varint_to_bytes(((value << 1) ^ (value >> 63)) as u64, out);
+}
+
+fn varint_size(value: i64) -> usize {
+    let mut v = ((value << 1) ^ (value >> 63)) as u64;
+    let mut size = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        size += 1;
+    }
+    size
+}
+
+fn unsigned_varint_to_bytes(value: u64, out: &mut BytesMut) {
+    let mut v = value;
+    out.reserve(10);
+    while v >= 0x80 {
+        out.put_u8((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    out.put_u8(v as u8);
+}
+
+fn varint_bytes_to_bytes(value: &Option<Vec<u8>>, out: &mut BytesMut) {
+    match *value {
+        None => varint_to_bytes(-1, out),
+        Some(ref v) => {
+            varint_to_bytes(v.len() as i64, out);
+            out.extend_from_slice(v);
+        }
+    }
+}
+
+fn record_to_bytes(record: &Record, first_offset: u64, first_timestamp: i64, out: &mut BytesMut) {
+    let mut buf = BytesMut::with_capacity(32 + opt_size(&record.key) + opt_size(&record.value));
+    buf.put_u8(0); // attributes
+    varint_to_bytes(record.timestamp - first_timestamp, &mut buf); // timestamp delta
+    varint_to_bytes((record.offset - first_offset) as i64, &mut buf); // offset delta
+    varint_bytes_to_bytes(&record.key, &mut buf);
+    varint_bytes_to_bytes(&record.value, &mut buf);
+    varint_to_bytes(record.headers.len() as i64, &mut buf);
+    for &(ref name, ref value) in &record.headers {
+        varint_to_bytes(name.len() as i64, &mut buf);
+        buf.extend_from_slice(name.as_bytes());
+        varint_bytes_to_bytes(value, &mut buf);
+    }
+    varint_to_bytes(buf.len() as i64, out);
+    out.extend(buf.take());
+}
+
+// All the records go into a single record batch (magic 2)
+fn record_batch_to_bytes(records: &Vec<Record>, codec: Compression, log_append_time: bool, out: &mut BytesMut) {
+    let (first, last, first_timestamp) = match (records.first(), records.last()) {
+        (Some(f), Some(l)) => (f.offset, l.offset, f.timestamp),
+        _ => return
+    };
+    let max_timestamp = records.iter().map(|r| r.timestamp).max().unwrap_or(-1);
+    let mut body = BytesMut::with_capacity(1024);
+    for r in records {
+        record_to_bytes(r, first, first_timestamp, &mut body);
+    }
+    let body = compression::compress(codec, &body[..]).expect("Failed to compress the records");
+    // Everything after the crc field, this is what the crc is calculated on
+    let mut batch = BytesMut::with_capacity(40 + body.len());
+    batch.put_u16::<BigEndian>((codec.attributes() | if log_append_time { LOG_APPEND_TIME } else { 0x00 }) as u16); // attributes
+    batch.put_u32::<BigEndian>((last - first) as u32); // last offset delta
+    batch.put_i64::<BigEndian>(first_timestamp); // first timestamp
+    batch.put_i64::<BigEndian>(max_timestamp); // max timestamp
+    batch.put_i64::<BigEndian>(-1); // producer id
+    batch.put_i16::<BigEndian>(-1); // producer epoch
+    batch.put_i32::<BigEndian>(-1); // base sequence
+    batch.put_u32::<BigEndian>(records.len() as u32);
+    batch.put(&body);
+    out.reserve(21 + batch.len());
+    out.put_u64::<BigEndian>(first); // base offset
+    out.put_u32::<BigEndian>(9 + batch.len() as u32); // batch length
+    out.put_u32::<BigEndian>(0); // partition leader epoch
+    out.put_u8(0x02); // magic
+    out.put_u32::<BigEndian>(crc32::checksum_castagnoli(&batch[..])); // crc32c
+    out.extend(batch.take());
+}
+
+fn fetch_to_bytes(version: i16, msg: &Vec<(String, Vec<FetchPartition>)>, out: &mut BytesMut) {
+    out.reserve(10);
+    if version >= 1 {
+        out.put_u32::<BigEndian>(0); // throttle
+    }
+    if version >= 7 {
+        out.put_u16::<BigEndian>(0); // error code
+        out.put_u32::<BigEndian>(0); // session id, we do not support incremental fetch sessions
+    }
     out.put_u32::<BigEndian>(msg.len() as u32);
     for topic in msg {
+        out.reserve(6 + topic.0.len());
         string_to_bytes(&topic.0, out);
-        out.put_u32::<BigEndian>(1u32); // only one zero partition in the response
-        out.put_u32::<BigEndian>(0); // partition. Always zero for now :)
-        out.put_u16::<BigEndian>(0); // error code
-        out.put_u64::<BigEndian>(0); // high watermark
-        // awesome Kafka wire format. We have to double buf it here to know the size of the RECORDS
-        let mut buf = BytesMut::with_capacity(1024);
-        records_to_bytes(&topic.1, &mut buf);
-        out.put_u32::<BigEndian>(buf.len() as u32);
-        out.extend(buf.take());
+        out.put_u32::<BigEndian>(topic.1.len() as u32);
+        for p in &topic.1 {
+            out.reserve(50);
+            out.put_u32::<BigEndian>(p.partition);
+            out.put_u16::<BigEndian>(p.error_code);
+            out.put_i64::<BigEndian>(p.high_watermark);
+            if version >= 4 {
+                out.put_i64::<BigEndian>(p.high_watermark); // last stable offset, no transactions here
+                if version >= 5 {
+                    out.put_i64::<BigEndian>(p.log_start_offset);
+                }
+                out.put_u32::<BigEndian>(0); // aborted transactions
+                if version >= 11 {
+                    out.put_i32::<BigEndian>(-1); // preferred read replica
+                }
+            }
+            // awesome Kafka wire format. We have to double buf it here to know the size of the RECORDS
+            let mut buf = BytesMut::with_capacity(1024);
+            partition_records_to_bytes(version, p, &mut buf);
+            out.reserve(4 + buf.len());
+            out.put_u32::<BigEndian>(buf.len() as u32);
+            out.extend(buf.take());
+        }
+    }
+}
+
+fn partition_records_to_bytes(version: i16, p: &FetchPartition, out: &mut BytesMut) {
+    match (version >= 4, p.compression) {
+        (true, codec)              => record_batch_to_bytes(&p.records, codec, p.log_append_time, out),
+        (false, Compression::None) => records_to_bytes(&p.records, p.log_append_time, out),
+        (false, codec)             => compressed_records_to_bytes(&p.records, codec, p.log_append_time, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::IResult;
+    use parser::{kafka_request, KafkaRequest, ApiRequest};
+
+    fn varint(value: i64) -> Vec<u8> {
+        let mut out = BytesMut::with_capacity(10);
+        varint_to_bytes(value, &mut out);
+        out.to_vec()
+    }
+
+    // A produce v3 request of the batch, the way the clients send them
+    fn produce(batch: &BytesMut) -> BytesMut {
+        let mut out = BytesMut::with_capacity(64 + batch.len());
+        out.put_u16::<BigEndian>(0); // produce
+        out.put_u16::<BigEndian>(3);
+        out.put_u32::<BigEndian>(1); // correlation id
+        string_to_bytes("test", &mut out); // client id
+        out.put_i16::<BigEndian>(-1); // transactional id
+        out.put_i16::<BigEndian>(1); // acks
+        out.put_u32::<BigEndian>(1000); // timeout
+        out.put_u32::<BigEndian>(1);
+        string_to_bytes("t", &mut out);
+        out.put_u32::<BigEndian>(1);
+        out.put_u32::<BigEndian>(0); // partition
+        out.put_u32::<BigEndian>(batch.len() as u32);
+        out.put(&batch[..]);
+        out
+    }
+
+    #[test]
+    fn varint_edge_cases() {
+        assert_eq!(varint(0), vec![0x00]);
+        assert_eq!(varint(-1), vec![0x01]);
+        assert_eq!(varint(1), vec![0x02]);
+        assert_eq!(varint(::std::i32::MAX as i64), vec![0xfe, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(varint(::std::i32::MIN as i64), vec![0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(varint(::std::i64::MAX), vec![0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert_eq!(varint(::std::i64::MIN), vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn record_sizes_match_the_wire() {
+        let records: Vec<Record> = (0..200).map(|i| Record {
+            offset: 5000 + i * 3, // compacted topics have gaps
+            timestamp: 1508000000000 + i as i64 * 1000,
+            key: if i % 4 == 0 { None } else { Some(vec![7; i as usize % 20]) },
+            value: if i % 7 == 0 { None } else { Some(vec![9; i as usize * 3]) },
+            headers: if i % 3 == 0 { vec![("trace".to_string(), Some(vec![1; i as usize])), ("none".to_string(), None)] } else { Vec::new() }
+        }).collect();
+        for &version in &[0, 3, 4, 11] {
+            let partition = FetchPartition { partition: 0, error_code: NONE, compression: Compression::None, log_append_time: false,
+                                             high_watermark: 0, log_start_offset: 0, records: records.clone() };
+            let size: usize = records.iter().map(|r| r.size(version, &records[0])).sum();
+            assert_eq!(size + records_overhead(version, Compression::None), records_size(version, &partition), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn record_batch_round_trip() {
+        let records: Vec<Record> = (0..300).map(|i| Record {
+            offset: 1000 + i,
+            timestamp: 1508000000000 + i as i64 * 7,
+            key: if i % 3 == 0 { None } else { Some(format!("key-{}", i).into_bytes()) },
+            value: if i % 5 == 0 { None } else { Some(vec![i as u8; i as usize]) },
+            headers: if i % 2 == 0 { Vec::new() } else { vec![("h".to_string(), Some(vec![1, 2])), ("empty".to_string(), None)] }
+        }).collect();
+        for codec in &[Compression::None, Compression::Gzip, Compression::Snappy, Compression::Lz4] {
+            let mut batch = BytesMut::with_capacity(1024);
+            record_batch_to_bytes(&records, *codec, false, &mut batch);
+            match kafka_request(&produce(&batch)[..]) {
+                IResult::Done(rest, KafkaRequest { req: ApiRequest::Publish { ref topics, .. }, .. }) => {
+                    assert!(rest.is_empty());
+                    let messages = topics[0].messages[0].1.as_ref().expect("Corrupt batch");
+                    assert_eq!(messages.len(), records.len(), "{:?}", codec);
+                    for (m, r) in messages.iter().zip(records.iter()) {
+                        assert_eq!(m.timestamp, r.timestamp);
+                        assert_eq!(m.key, r.key);
+                        assert_eq!(m.value, r.value);
+                        assert_eq!(m.headers, r.headers);
+                    }
+                },
+                other => panic!("Failed to parse the {:?} batch {:?}", codec, other)
+            }
+        }
     }
 }